@@ -0,0 +1,156 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::permissions::Permissions;
+
+fn parse_net(value: &str) -> anyhow::Result<IpNet> {
+    if let Ok(net) = value.parse::<IpNet>() {
+        return Ok(net);
+    }
+    // Plain addresses match only themselves
+    match value.parse::<IpAddr>() {
+        Ok(addr) => Ok(IpNet::from(addr)),
+        Err(_) => Err(anyhow::anyhow!("Invalid IP address or CIDR range: {value}")),
+    }
+}
+
+/// Network range that may connect, optionally with its own default permissions
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowRule {
+    net: IpNet,
+    permissions: Option<Permissions>,
+}
+
+impl std::str::FromStr for AllowRule {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<AllowRule> {
+        let (net, permissions) = match value.split_once('=') {
+            Some((net, permissions)) => (net, Some(Permissions::parse(permissions)?)),
+            None => (value, None),
+        };
+        Ok(AllowRule {
+            net: parse_net(net)?,
+            permissions,
+        })
+    }
+}
+
+/// Network range that may not connect
+#[derive(Debug, Clone, PartialEq)]
+pub struct DenyRule {
+    net: IpNet,
+}
+
+impl std::str::FromStr for DenyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<DenyRule> {
+        Ok(DenyRule {
+            net: parse_net(value)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessControl {
+    pub allow: Vec<AllowRule>,
+    pub deny: Vec<DenyRule>,
+}
+
+impl AccessControl {
+    /// Rules for one listener: its deny rules add to these, its allow rules replace these if it has any
+    pub fn with_listener(&self, listener: &AccessControl) -> AccessControl {
+        AccessControl {
+            allow: match listener.allow.is_empty() {
+                true => self.allow.clone(),
+                false => listener.allow.clone(),
+            },
+            deny: self.deny.iter().chain(&listener.deny).cloned().collect(),
+        }
+    }
+
+    /// Permissions for a new connection from this address, or None if it should be rejected.
+    /// Deny rules win, and if any allow rules exist the address needs to match one of them.
+    pub fn check(&self, addr: IpAddr, default_permissions: Permissions) -> Option<Permissions> {
+        // Dual stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses
+        let addr = addr.to_canonical();
+        if self.deny.iter().any(|rule| rule.net.contains(&addr)) {
+            return None;
+        }
+        if self.allow.is_empty() {
+            return Some(default_permissions);
+        }
+        // The most specific matching range decides
+        let rule = self.allow.iter()
+            .filter(|rule| rule.net.contains(&addr))
+            .max_by_key(|rule| rule.net.prefix_len())?;
+        Some(rule.permissions.unwrap_or(default_permissions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(allow: &[&str], deny: &[&str]) -> AccessControl {
+        AccessControl {
+            allow: allow.iter().map(|rule| rule.parse().unwrap()).collect(),
+            deny: deny.iter().map(|rule| rule.parse().unwrap()).collect(),
+        }
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn no_rules_allow_everyone() {
+        assert_eq!(access(&[], &[]).check(ip("203.0.113.7"), Permissions::READ), Some(Permissions::READ));
+    }
+
+    #[test]
+    fn most_specific_allow_decides() {
+        let access = access(&["192.168.0.0/16=read", "192.168.1.0/24=read,control", "192.168.1.5"], &[]);
+        assert_eq!(access.check(ip("192.168.2.1"), Permissions::ALL), Some(Permissions::READ));
+        assert_eq!(access.check(ip("192.168.1.1"), Permissions::ALL), Some(Permissions::READ | Permissions::CONTROL));
+        // Without permissions of its own, a rule grants the default ones
+        assert_eq!(access.check(ip("192.168.1.5"), Permissions::ALL), Some(Permissions::ALL));
+        assert_eq!(access.check(ip("10.0.0.1"), Permissions::ALL), None);
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = access(&["10.0.0.0/8", "10.0.0.1"], &["10.0.0.0/24"]);
+        assert_eq!(access.check(ip("10.0.0.1"), Permissions::READ), None);
+        assert_eq!(access.check(ip("10.0.1.1"), Permissions::READ), Some(Permissions::READ));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_rules() {
+        let access = access(&["127.0.0.1"], &[]);
+        assert_eq!(access.check(ip("::ffff:127.0.0.1"), Permissions::READ), Some(Permissions::READ));
+        assert_eq!(access.check(ip("::1"), Permissions::READ), None);
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!("localhost".parse::<AllowRule>().is_err());
+        assert!("10.0.0.0/33".parse::<DenyRule>().is_err());
+        assert!("10.0.0.0/8=write".parse::<AllowRule>().is_err());
+    }
+
+    #[test]
+    fn listener_rules_combine_with_global_ones() {
+        let global = access(&["10.0.0.0/8"], &["10.0.0.1"]);
+        // Deny rules add up
+        let combined = global.with_listener(&access(&[], &["10.0.0.2"]));
+        assert_eq!(combined, access(&["10.0.0.0/8"], &["10.0.0.1", "10.0.0.2"]));
+        // Allow rules of the listener replace the global ones
+        let combined = global.with_listener(&access(&["192.168.0.0/16=read"], &[]));
+        assert_eq!(combined, access(&["192.168.0.0/16=read"], &["10.0.0.1"]));
+        assert_eq!(combined.check(ip("10.1.0.1"), Permissions::ALL), None);
+        assert_eq!(combined.check(ip("192.168.0.1"), Permissions::ALL), Some(Permissions::READ));
+    }
+}
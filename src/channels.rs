@@ -0,0 +1,207 @@
+use log::{debug, warn};
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+/// Same limit as MPD uses for unread messages per client
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// Same limit as MPD uses for subscriptions per client
+const MAX_SUBSCRIPTIONS: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum ChannelError {
+    InvalidName,
+    AlreadySubscribed,
+    TooManySubscriptions,
+    NotSubscribed,
+    NoReceiver,
+}
+
+impl std::fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChannelError::InvalidName => write!(f, "invalid channel name"),
+            ChannelError::AlreadySubscribed => write!(f, "already subscribed to this channel"),
+            ChannelError::TooManySubscriptions => write!(f, "subscription list is full"),
+            ChannelError::NotSubscribed => write!(f, "not subscribed to this channel"),
+            ChannelError::NoReceiver => write!(f, "nobody is subscribed to this channel"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClientChannels {
+    subscriptions: BTreeSet<String>,
+    messages: VecDeque<(String, String)>,
+}
+
+/// Channel subscriptions and message queues of all connected MPD clients
+#[derive(Default)]
+pub struct ChannelRegistry {
+    next_client_id: u64,
+    clients: HashMap<u64, ClientChannels>,
+}
+
+fn is_valid_channel_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+}
+
+impl ChannelRegistry {
+    pub fn register_client(&mut self) -> u64 {
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+        self.clients.insert(id, ClientChannels::default());
+        id
+    }
+
+    /// Returns true if the client was subscribed to any channel
+    pub fn unregister_client(&mut self, id: u64) -> bool {
+        self.clients.remove(&id)
+            .is_some_and(|client| !client.subscriptions.is_empty())
+    }
+
+    pub fn subscribe(&mut self, id: u64, channel: &str) -> Result<(), ChannelError> {
+        if !is_valid_channel_name(channel) {
+            return Err(ChannelError::InvalidName);
+        }
+        let client = self.clients.entry(id).or_default();
+        if client.subscriptions.len() >= MAX_SUBSCRIPTIONS && !client.subscriptions.contains(channel) {
+            return Err(ChannelError::TooManySubscriptions);
+        }
+        if !client.subscriptions.insert(channel.to_string()) {
+            return Err(ChannelError::AlreadySubscribed);
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, id: u64, channel: &str) -> Result<(), ChannelError> {
+        let removed = self.clients.get_mut(&id)
+            .map(|client| client.subscriptions.remove(channel))
+            .unwrap_or(false);
+        if !removed {
+            return Err(ChannelError::NotSubscribed);
+        }
+        Ok(())
+    }
+
+    /// All channels at least one client is subscribed to
+    pub fn channels(&self) -> BTreeSet<&str> {
+        self.clients.values()
+            .flat_map(|client| client.subscriptions.iter().map(|c| c.as_str()))
+            .collect()
+    }
+
+    /// Queue a message for all subscribers of a channel, returns the clients that got it
+    pub fn send_message(&mut self, channel: &str, message: &str) -> Result<Vec<u64>, ChannelError> {
+        if !is_valid_channel_name(channel) {
+            return Err(ChannelError::InvalidName);
+        }
+        let mut subscribers = 0;
+        let mut receivers = Vec::new();
+        for (id, client) in self.clients.iter_mut() {
+            if !client.subscriptions.contains(channel) {
+                continue;
+            }
+            subscribers += 1;
+            if client.messages.len() >= MAX_PENDING_MESSAGES {
+                warn!("Dropping message on {channel} for client {id} with too many unread messages");
+                continue;
+            }
+            client.messages.push_back((channel.to_string(), message.to_string()));
+            receivers.push(*id);
+        }
+        debug!("Sent message on {channel} to {subscribers} clients");
+        if subscribers == 0 {
+            return Err(ChannelError::NoReceiver);
+        }
+        Ok(receivers)
+    }
+
+    pub fn read_messages(&mut self, id: u64) -> Vec<(String, String)> {
+        self.clients.get_mut(&id)
+            .map(|client| client.messages.drain(..).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_names() {
+        let mut channels = ChannelRegistry::default();
+        let id = channels.register_client();
+        assert_eq!(channels.subscribe(id, "mpd-mpris-bridge"), Ok(()));
+        assert_eq!(channels.subscribe(id, "home:lights_1.on"), Ok(()));
+        for name in ["", "with space", "slash/es", "ümlaut"] {
+            assert_eq!(channels.subscribe(id, name), Err(ChannelError::InvalidName));
+            assert_eq!(channels.send_message(name, "hi"), Err(ChannelError::InvalidName));
+        }
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut channels = ChannelRegistry::default();
+        let first = channels.register_client();
+        let second = channels.register_client();
+        assert_ne!(first, second);
+        assert_eq!(channels.subscribe(first, "a"), Ok(()));
+        assert_eq!(channels.subscribe(first, "a"), Err(ChannelError::AlreadySubscribed));
+        assert_eq!(channels.subscribe(second, "a"), Ok(()));
+        assert_eq!(channels.subscribe(second, "b"), Ok(()));
+        assert_eq!(channels.channels(), BTreeSet::from(["a", "b"]));
+        assert_eq!(channels.unsubscribe(second, "b"), Ok(()));
+        assert_eq!(channels.unsubscribe(second, "b"), Err(ChannelError::NotSubscribed));
+        assert_eq!(channels.channels(), BTreeSet::from(["a"]));
+        assert!(channels.unregister_client(first));
+        assert_eq!(channels.unsubscribe(second, "a"), Ok(()));
+        assert!(!channels.unregister_client(second));
+        assert!(channels.channels().is_empty());
+    }
+
+    #[test]
+    fn subscription_limit() {
+        let mut channels = ChannelRegistry::default();
+        let id = channels.register_client();
+        for i in 0..MAX_SUBSCRIPTIONS {
+            assert_eq!(channels.subscribe(id, &format!("channel{i}")), Ok(()));
+        }
+        assert_eq!(channels.subscribe(id, "one-more"), Err(ChannelError::TooManySubscriptions));
+        assert_eq!(channels.subscribe(id, "channel0"), Err(ChannelError::AlreadySubscribed));
+        assert_eq!(channels.unsubscribe(id, "channel0"), Ok(()));
+        assert_eq!(channels.subscribe(id, "one-more"), Ok(()));
+    }
+
+    #[test]
+    fn messages_reach_subscribers_only() {
+        let mut channels = ChannelRegistry::default();
+        let sender = channels.register_client();
+        let receiver = channels.register_client();
+        assert_eq!(channels.send_message("a", "nobody"), Err(ChannelError::NoReceiver));
+        assert_eq!(channels.subscribe(receiver, "a"), Ok(()));
+        assert_eq!(channels.send_message("a", "one"), Ok(vec![receiver]));
+        assert_eq!(channels.send_message("a", "two"), Ok(vec![receiver]));
+        assert!(channels.read_messages(sender).is_empty());
+        let expected = vec![("a".to_string(), "one".to_string()), ("a".to_string(), "two".to_string())];
+        assert_eq!(channels.read_messages(receiver), expected);
+        // Reading drains the queue
+        assert!(channels.read_messages(receiver).is_empty());
+    }
+
+    #[test]
+    fn message_limit() {
+        let mut channels = ChannelRegistry::default();
+        let id = channels.register_client();
+        assert_eq!(channels.subscribe(id, "a"), Ok(()));
+        for i in 0..MAX_PENDING_MESSAGES {
+            assert_eq!(channels.send_message("a", &i.to_string()), Ok(vec![id]));
+        }
+        // Still subscribed, so the message is dropped rather than refused
+        assert_eq!(channels.send_message("a", "dropped"), Ok(Vec::new()));
+        let messages = channels.read_messages(id);
+        assert_eq!(messages.len(), MAX_PENDING_MESSAGES);
+        assert_eq!(messages.last().map(|(_, message)| message.clone()), Some((MAX_PENDING_MESSAGES - 1).to_string()));
+        assert_eq!(channels.send_message("a", "again"), Ok(vec![id]));
+    }
+}
@@ -0,0 +1,426 @@
+use log::{debug, info, warn};
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::access::{AccessControl, AllowRule, DenyRule};
+use crate::listener::{self, ListenerConfig};
+use crate::permissions::{PasswordEntry, Permissions};
+use crate::selection::{PlayerPattern, SelectionPolicy, SwitchMode};
+use crate::{ducking, history, sticker, Args};
+
+const DEFAULT_PORT: u16 = 6601;
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_DUCK_VOLUME_PERCENT: u8 = 20;
+const DEFAULT_DUCK_RAMP_MS: u64 = 500;
+const DEFAULT_FAIL_DELAY_MS: u64 = 1500;
+const DEFAULT_SLOW_POLL_MS: u64 = 1000;
+const DEFAULT_FALLBACK_POLL_MS: u64 = 5000;
+const DEFAULT_FAST_POLL_MS: u64 = 100;
+const DEFAULT_COMMAND_TIMEOUT_MS: u64 = 5000;
+
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("mpd-mpris-bridge").join("config.toml"))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SocketConfig {
+    Enabled(bool),
+    Path(PathBuf),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct TimingConfig {
+    fail_delay_ms: Option<u64>,
+    slow_poll_ms: Option<u64>,
+    fallback_poll_ms: Option<u64>,
+    fast_poll_ms: Option<u64>,
+    /// No longer used, idle is notified of changes right away
+    idle_poll_ms: Option<u64>,
+    command_timeout_ms: Option<u64>,
+}
+
+/// Contents of the config file, keys are named like the command line options
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    port: Option<u16>,
+    bind_address: Option<String>,
+    history_file: Option<PathBuf>,
+    no_history: Option<bool>,
+    sticker_file: Option<PathBuf>,
+    no_sticker_file: Option<bool>,
+    volume_file: Option<PathBuf>,
+    no_volume_file: Option<bool>,
+    password: Vec<String>,
+    default_permissions: Option<String>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    listen: Vec<String>,
+    /// true for the default path, or the socket path
+    socket: Option<SocketConfig>,
+    socket_mode: Option<String>,
+    socket_trust_owner: Option<bool>,
+    no_tcp: Option<bool>,
+    shutdown_timeout: Option<u64>,
+    /// Version announced to clients, unless a listener overrides it
+    protocol_version: Option<String>,
+    /// Player to bridge instead of following the active one
+    player: Option<String>,
+    player_allow: Vec<String>,
+    player_deny: Vec<String>,
+    player_priority: Vec<String>,
+    switch_mode: Option<String>,
+    switch_delay: Option<u64>,
+    exclusive_playback: Option<bool>,
+    exclusive_exempt: Vec<String>,
+    auto_resume: Option<bool>,
+    resume_rewind: Option<u64>,
+    duck_foreground: Vec<String>,
+    duck_volume: Option<u8>,
+    duck_ramp_ms: Option<u64>,
+    command_ttl: Option<u64>,
+    timing: TimingConfig,
+}
+
+impl ConfigFile {
+    /// Load the config file, which may only be missing if it is not explicitly requested
+    fn load(path: Option<&Path>) -> anyhow::Result<ConfigFile> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(ConfigFile::default()),
+            },
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+                debug!("No config file at {}", path.display());
+                return Ok(ConfigFile::default());
+            }
+            Err(e) => return Err(anyhow::anyhow!("Cannot read config {}: {e}", path.display())),
+        };
+        let config = toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Invalid config {}: {e}", path.display()))?;
+        info!("Loaded config from {}", path.display());
+        Ok(ConfigFile {
+            path: Some(path),
+            ..config
+        })
+    }
+}
+
+/// Effective settings from the command line, the config file and defaults, in this order
+pub struct Settings {
+    pub history_path: Option<PathBuf>,
+    pub sticker_path: Option<PathBuf>,
+    pub volume_path: Option<PathBuf>,
+    /// Address of the default listener, None if disabled
+    pub tcp_address: Option<String>,
+    pub listeners: Vec<ListenerConfig>,
+    pub socket_path: Option<PathBuf>,
+    pub socket_mode: u32,
+    pub socket_trust_owner: bool,
+    pub passwords: Vec<PasswordEntry>,
+    pub default_permissions: Permissions,
+    pub access_control: AccessControl,
+    pub shutdown_timeout: Duration,
+    pub protocol_version: String,
+    pub player: Option<String>,
+    pub selection: SelectionPolicy,
+    pub switch_mode: SwitchMode,
+    /// How long another player must play before it takes over the bridge
+    pub switch_delay: Duration,
+    pub exclusive_playback: bool,
+    pub exclusive_exempt: Vec<PlayerPattern>,
+    pub auto_resume: bool,
+    pub resume_rewind: Duration,
+    /// Players whose playback ducks the others
+    pub duck_foreground: Vec<PlayerPattern>,
+    /// Fraction of the original volume while ducked
+    pub duck_level: f64,
+    pub duck_ramp: Duration,
+    pub fail_delay: Duration,
+    pub slow_poll_delay: Duration,
+    /// Poll delay while player changes arrive as signals
+    pub fallback_poll_delay: Duration,
+    pub fast_poll_delay: Duration,
+    /// How long commands wait for a player to appear, zero to reject them right away
+    pub command_ttl: Duration,
+    /// How long clients wait for the player to take or execute a command
+    pub command_timeout: Duration,
+}
+
+/// Parse a list from the config, naming the bad entry on errors
+fn parse_list<T, F>(key: &str, values: &[String], parse: F) -> anyhow::Result<Vec<T>>
+where
+    F: Fn(&str) -> anyhow::Result<T>,
+{
+    values.iter()
+        .enumerate()
+        .map(|(i, value)| parse(value).map_err(|e| anyhow::anyhow!("{key}[{i}]: {e}")))
+        .collect()
+}
+
+fn parse_value<T, F>(key: &str, value: Option<&str>, parse: F) -> anyhow::Result<Option<T>>
+where
+    F: Fn(&str) -> anyhow::Result<T>,
+{
+    value.map(|value| parse(value).map_err(|e| anyhow::anyhow!("{key}: {e}"))).transpose()
+}
+
+fn millis(key: &str, value: Option<u64>, default: u64) -> anyhow::Result<Duration> {
+    match value.unwrap_or(default) {
+        0 => Err(anyhow::anyhow!("{key}: must be greater than 0")),
+        ms => Ok(Duration::from_millis(ms)),
+    }
+}
+
+impl Settings {
+    /// Read the config file and combine it with the command line
+    pub fn load(args: &Args) -> anyhow::Result<Settings> {
+        let file = ConfigFile::load(args.config.as_deref())?;
+        let path = file.path.clone();
+        Settings::resolve(args, file).map_err(|e| match path {
+            Some(path) => anyhow::anyhow!("Invalid config {}: {e}", path.display()),
+            None => e,
+        })
+    }
+
+    fn resolve(args: &Args, file: ConfigFile) -> anyhow::Result<Settings> {
+        let history_path = match args.no_history || file.no_history.unwrap_or(false) {
+            true => None,
+            false => args.history_file.clone().or(file.history_file).or_else(history::default_history_path),
+        };
+        let sticker_path = match args.no_sticker_file || file.no_sticker_file.unwrap_or(false) {
+            true => None,
+            false => args.sticker_file.clone().or(file.sticker_file).or_else(sticker::default_sticker_path),
+        };
+        let volume_path = match args.no_volume_file || file.no_volume_file.unwrap_or(false) {
+            true => None,
+            false => args.volume_file.clone().or(file.volume_file).or_else(ducking::default_volume_path),
+        };
+        let tcp_address = match args.no_tcp || file.no_tcp.unwrap_or(false) {
+            true => None,
+            false => Some(format!(
+                "{}:{}",
+                args.bind_address.clone().or(file.bind_address).unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_string()),
+                args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            )),
+        };
+        let listeners = match args.listeners.is_empty() {
+            true => parse_list("listen", &file.listen, |value| value.parse())?,
+            false => args.listeners.clone(),
+        };
+        let socket_path = match (&args.socket, file.socket) {
+            (Some(Some(path)), _) => Some(path.clone()),
+            (Some(None), _) | (None, Some(SocketConfig::Enabled(true))) => Some(listener::default_socket_path()
+                .ok_or_else(|| anyhow::anyhow!("No runtime directory for the default socket path, please pass one"))?),
+            (None, Some(SocketConfig::Path(path))) => Some(path),
+            (None, Some(SocketConfig::Enabled(false)) | None) => None,
+        };
+        let socket_mode = match args.socket_mode {
+            Some(mode) => mode,
+            None => parse_value("socket-mode", file.socket_mode.as_deref(), listener::parse_socket_mode)?
+                .unwrap_or(listener::DEFAULT_SOCKET_MODE),
+        };
+        let passwords = match args.passwords.is_empty() {
+            true => parse_list("password", &file.password, |value| value.parse())?,
+            false => args.passwords.clone(),
+        };
+        let default_permissions = match args.default_permissions {
+            Some(permissions) => Some(permissions),
+            None => parse_value("default-permissions", file.default_permissions.as_deref(), Permissions::parse)?,
+        };
+        let default_permissions = match default_permissions {
+            Some(permissions) => permissions,
+            None if passwords.is_empty() => Permissions::ALL,
+            None => Permissions::NONE,
+        };
+        let allow: Vec<AllowRule> = match args.allow_rules.is_empty() {
+            true => parse_list("allow", &file.allow, |value| value.parse())?,
+            false => args.allow_rules.clone(),
+        };
+        let deny: Vec<DenyRule> = match args.deny_rules.is_empty() {
+            true => parse_list("deny", &file.deny, |value| value.parse())?,
+            false => args.deny_rules.clone(),
+        };
+        let selection = SelectionPolicy {
+            allow: match args.player_allow.is_empty() {
+                true => parse_list("player-allow", &file.player_allow, |value| value.parse())?,
+                false => args.player_allow.clone(),
+            },
+            deny: match args.player_deny.is_empty() {
+                true => parse_list("player-deny", &file.player_deny, |value| value.parse())?,
+                false => args.player_deny.clone(),
+            },
+            priority: match args.player_priority.is_empty() {
+                true => parse_list("player-priority", &file.player_priority, |value| value.parse())?,
+                false => args.player_priority.clone(),
+            },
+        };
+        let switch_mode = match args.switch_mode {
+            Some(mode) => mode,
+            None => parse_value("switch-mode", file.switch_mode.as_deref(), |value| value.parse())?.unwrap_or_default(),
+        };
+        let exclusive_exempt = match args.exclusive_exempt.is_empty() {
+            true => parse_list("exclusive-exempt", &file.exclusive_exempt, |value| value.parse())?,
+            false => args.exclusive_exempt.clone(),
+        };
+        let duck_foreground = match args.duck_foreground.is_empty() {
+            true => parse_list("duck-foreground", &file.duck_foreground, |value| value.parse())?,
+            false => args.duck_foreground.clone(),
+        };
+        let duck_volume = args.duck_volume.or(file.duck_volume).unwrap_or(DEFAULT_DUCK_VOLUME_PERCENT);
+        if duck_volume > 100 {
+            return Err(anyhow::anyhow!("duck-volume: must be at most 100"));
+        }
+        if file.timing.idle_poll_ms.is_some() {
+            warn!("timing.idle-poll-ms is obsolete and ignored, idle no longer polls");
+        }
+        let protocol_version = parse_value("protocol-version", file.protocol_version.as_deref(), listener::parse_protocol_version)?
+            .unwrap_or_else(|| listener::PROTOCOL_VERSION.to_string());
+        Ok(Settings {
+            history_path,
+            sticker_path,
+            volume_path,
+            tcp_address,
+            listeners,
+            socket_path,
+            socket_mode,
+            socket_trust_owner: args.socket_trust_owner || file.socket_trust_owner.unwrap_or(false),
+            passwords,
+            default_permissions,
+            access_control: AccessControl { allow, deny },
+            shutdown_timeout: Duration::from_secs(args.shutdown_timeout
+                .or(file.shutdown_timeout)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS)),
+            protocol_version,
+            player: file.player.filter(|player| !player.is_empty()),
+            selection,
+            switch_mode,
+            switch_delay: Duration::from_secs(args.switch_delay.or(file.switch_delay).unwrap_or(0)),
+            exclusive_playback: args.exclusive_playback || file.exclusive_playback.unwrap_or(false),
+            exclusive_exempt,
+            auto_resume: args.auto_resume || file.auto_resume.unwrap_or(false),
+            resume_rewind: Duration::from_secs(args.resume_rewind.or(file.resume_rewind).unwrap_or(0)),
+            duck_foreground,
+            duck_level: f64::from(duck_volume) / 100.0,
+            duck_ramp: Duration::from_millis(args.duck_ramp_ms.or(file.duck_ramp_ms).unwrap_or(DEFAULT_DUCK_RAMP_MS)),
+            fail_delay: millis("timing.fail-delay-ms", file.timing.fail_delay_ms, DEFAULT_FAIL_DELAY_MS)?,
+            slow_poll_delay: millis("timing.slow-poll-ms", file.timing.slow_poll_ms, DEFAULT_SLOW_POLL_MS)?,
+            fallback_poll_delay: millis("timing.fallback-poll-ms", file.timing.fallback_poll_ms, DEFAULT_FALLBACK_POLL_MS)?,
+            fast_poll_delay: millis("timing.fast-poll-ms", file.timing.fast_poll_ms, DEFAULT_FAST_POLL_MS)?,
+            command_ttl: Duration::from_secs(args.command_ttl.or(file.command_ttl).unwrap_or(0)),
+            command_timeout: millis("timing.command-timeout-ms", file.timing.command_timeout_ms, DEFAULT_COMMAND_TIMEOUT_MS)?,
+        })
+    }
+
+    /// Names of settings that differ and only take effect after a restart
+    pub fn restart_required(&self, other: &Settings) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.history_path != other.history_path {
+            changed.push("history-file");
+        }
+        if self.sticker_path != other.sticker_path {
+            changed.push("sticker-file");
+        }
+        if self.volume_path != other.volume_path {
+            changed.push("volume-file");
+        }
+        if self.tcp_address != other.tcp_address {
+            changed.push("bind-address/port");
+        }
+        if self.listeners != other.listeners {
+            changed.push("listen");
+        }
+        if self.socket_path != other.socket_path || self.socket_mode != other.socket_mode ||
+            self.socket_trust_owner != other.socket_trust_owner
+        {
+            changed.push("socket");
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn resolve(args: &[&str], config: &str) -> anyhow::Result<Settings> {
+        let args = Args::parse_from(["mpd-mpris-bridge"].iter().chain(args));
+        Settings::resolve(&args, toml::from_str(config)?)
+    }
+
+    fn resolve_error(config: &str) -> String {
+        match resolve(&[], config) {
+            Ok(_) => panic!("{config} should be rejected"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = resolve(&[], "").unwrap();
+        assert_eq!(settings.tcp_address.as_deref(), Some("0.0.0.0:6601"));
+        assert_eq!(settings.default_permissions, Permissions::ALL);
+        assert_eq!(settings.switch_mode, SwitchMode::Playing);
+        assert_eq!(settings.duck_level, 0.2);
+        assert_eq!(settings.fallback_poll_delay, Duration::from_millis(DEFAULT_FALLBACK_POLL_MS));
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let config = "port = 6700\nbind-address = \"127.0.0.1\"\nswitch-mode = \"follow\"\nduck-volume = 50";
+        let settings = resolve(&["--port", "6800", "--switch-mode", "pinned"], config).unwrap();
+        assert_eq!(settings.tcp_address.as_deref(), Some("127.0.0.1:6800"));
+        assert_eq!(settings.switch_mode, SwitchMode::Pinned);
+        assert_eq!(settings.duck_level, 0.5);
+    }
+
+    #[test]
+    fn passwords_restrict_default_permissions() {
+        let settings = resolve(&[], "password = [\"secret@read,control\"]").unwrap();
+        assert_eq!(settings.default_permissions, Permissions::NONE);
+        let settings = resolve(&[], "password = [\"secret@admin\"]\ndefault-permissions = \"read\"").unwrap();
+        assert_eq!(settings.default_permissions, Permissions::READ);
+    }
+
+    #[test]
+    fn invalid_values_name_the_key() {
+        for (config, key) in [
+            ("listen = [\"localhost:6600\", \"localhost\"]", "listen[1]"),
+            ("socket-mode = \"999\"", "socket-mode"),
+            ("password = [\"secret\"]", "password[0]"),
+            ("default-permissions = \"write\"", "default-permissions"),
+            ("allow = [\"10.0.0.0/8\", \"nowhere\"]", "allow[1]"),
+            ("deny = [\"nowhere\"]", "deny[0]"),
+            ("player-priority = [\"re:(\"]", "player-priority[0]"),
+            ("switch-mode = \"random\"", "switch-mode"),
+            ("duck-foreground = [\"\"]", "duck-foreground[0]"),
+            ("duck-volume = 101", "duck-volume"),
+            ("protocol-version = \"0.23\"", "protocol-version"),
+            ("[timing]\nfast-poll-ms = 0", "timing.fast-poll-ms"),
+            ("[timing]\ncommand-timeout-ms = 0", "timing.command-timeout-ms"),
+        ] {
+            let error = resolve_error(config);
+            assert!(error.starts_with(&format!("{key}: ")), "{config} failed with {error}");
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<ConfigFile>("prot = 6600").is_err());
+        assert!(toml::from_str::<ConfigFile>("[timing]\nslow-pol-ms = 10").is_err());
+    }
+}
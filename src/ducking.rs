@@ -0,0 +1,204 @@
+use log::{debug, info, warn};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use mpris::PlaybackStatus;
+
+use crate::selection::{FoundPlayer, PlayerPattern};
+
+pub fn default_volume_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("mpd-mpris-bridge").join("volumes.json"))
+}
+
+struct Ramp {
+    from: f64,
+    to: f64,
+    started: Instant,
+    /// Ramping back to the original volume, rather than down
+    restore: bool,
+}
+
+impl Ramp {
+    fn volume(&self, duration: Duration) -> f64 {
+        if duration.is_zero() {
+            return self.to;
+        }
+        let progress = (self.started.elapsed().as_secs_f64() / duration.as_secs_f64()).min(1.0);
+        self.from + (self.to - self.from) * progress
+    }
+}
+
+/// Lowers the volume of background players while a foreground player is playing
+pub struct Ducking {
+    path: Option<PathBuf>,
+    /// Volume before ducking by bus name, persisted so it survives a restart mid-duck
+    original: BTreeMap<String, f64>,
+    /// Players that reached the ducked volume
+    ducked: HashSet<String>,
+    ramps: HashMap<String, Ramp>,
+}
+
+impl Ducking {
+    /// Volume memory that is only kept in memory
+    pub fn in_memory() -> Ducking {
+        Ducking {
+            path: None,
+            original: BTreeMap::new(),
+            ducked: HashSet::new(),
+            ramps: HashMap::new(),
+        }
+    }
+
+    /// Load the volume memory from a JSON file, which does not need to exist yet
+    pub fn load(path: &Path) -> anyhow::Result<Ducking> {
+        let original: BTreeMap<String, f64> = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid volume memory {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No volume memory at {} yet", path.display());
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !original.is_empty() {
+            info!("Loaded volumes of {} ducked players from {}", original.len(), path.display());
+        }
+        Ok(Ducking {
+            path: Some(path.to_path_buf()),
+            original,
+            ..Ducking::in_memory()
+        })
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let write = || -> anyhow::Result<()> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Write to a temporary file first so we never leave a truncated file behind
+            let tmp_path = path.with_extension("json.tmp");
+            std::fs::write(&tmp_path, serde_json::to_string_pretty(&self.original)?)?;
+            std::fs::rename(&tmp_path, path)?;
+            Ok(())
+        };
+        if let Err(e) = write() {
+            warn!("Failed to persist volume memory {}: {e}", path.display());
+        }
+    }
+
+    /// Whether volumes still need to be restored, even if nothing is configured to duck them anymore
+    pub fn is_active(&self) -> bool {
+        !self.original.is_empty() || !self.ramps.is_empty()
+    }
+
+    /// Set ducked players back to their original volume right away, for when the bridge stops
+    pub fn restore_all(&mut self, players: &[FoundPlayer]) {
+        self.ramps.clear();
+        self.ducked.clear();
+        if self.original.is_empty() {
+            return;
+        }
+        for found in players {
+            let bus_name = found.player.bus_name();
+            let Some(original) = self.original.get(bus_name).copied() else {
+                continue;
+            };
+            info!("Restoring volume {original:.2} of {bus_name}");
+            match found.player.set_volume(original) {
+                Ok(()) => {
+                    self.original.remove(bus_name);
+                }
+                // Keep it to try again on the next start
+                Err(e) => warn!("Failed to restore volume of {bus_name}: {e}"),
+            }
+        }
+        self.persist();
+    }
+
+    /// Duck or restore players as needed and advance volume ramps, returns true while ramping
+    pub fn check(&mut self, players: &[FoundPlayer], foreground: &[PlayerPattern], level: f64, ramp_duration: Duration) -> bool {
+        // A player that comes back starts at its own volume again
+        self.ducked.retain(|bus_name| players.iter().any(|found| found.player.bus_name() == bus_name));
+        let is_foreground = |found: &FoundPlayer| foreground.iter().any(|pattern| pattern.matches(found));
+        let foreground_playing = players.iter()
+            .any(|found| is_foreground(found) && found.status == Some(PlaybackStatus::Playing));
+        for found in players.iter().filter(|found| !is_foreground(found)) {
+            let player = &found.player;
+            let bus_name = player.bus_name();
+            let original = match self.original.get(bus_name) {
+                Some(original) => *original,
+                None if foreground_playing && found.status == Some(PlaybackStatus::Playing) => {
+                    // Players without volume control cannot be ducked
+                    let Ok(volume) = player.get_volume() else {
+                        continue;
+                    };
+                    info!("Ducking {bus_name} from volume {volume:.2}");
+                    self.original.insert(bus_name.to_string(), volume);
+                    self.persist();
+                    volume
+                }
+                None => continue,
+            };
+            let is_ducking = self.ramps.get(bus_name)
+                .map(|ramp| !ramp.restore)
+                .or_else(|| self.ducked.contains(bus_name).then_some(true));
+            if is_ducking == Some(foreground_playing) {
+                continue;
+            }
+            if !foreground_playing {
+                info!("Restoring volume {original:.2} of {bus_name}");
+            }
+            let from = self.ramps.get(bus_name)
+                .map(|ramp| ramp.volume(ramp_duration))
+                .or_else(|| player.get_volume().ok())
+                .unwrap_or(original);
+            let to = match foreground_playing {
+                true => original * level,
+                false => original,
+            };
+            self.ducked.remove(bus_name);
+            self.ramps.insert(bus_name.to_string(), Ramp { from, to, started: Instant::now(), restore: !foreground_playing });
+        }
+        let mut finished = Vec::new();
+        for (bus_name, ramp) in &self.ramps {
+            let Some(found) = players.iter().find(|found| found.player.bus_name() == bus_name) else {
+                // Keep the original volume for when the player comes back
+                finished.push((bus_name.clone(), false));
+                continue;
+            };
+            // Decide first, so the last step sets exactly the target volume
+            let reached = ramp.started.elapsed() >= ramp_duration;
+            let volume = match reached {
+                true => ramp.to,
+                false => ramp.volume(ramp_duration),
+            };
+            if let Err(e) = found.player.set_volume(volume) {
+                warn!("Failed to set volume of {bus_name}: {e}");
+            }
+            if reached {
+                finished.push((bus_name.clone(), true));
+            }
+        }
+        for (bus_name, reached) in finished {
+            let Some(ramp) = self.ramps.remove(&bus_name) else {
+                continue;
+            };
+            match (reached, ramp.restore) {
+                (false, _) => {}
+                (true, true) => {
+                    self.original.remove(&bus_name);
+                    self.persist();
+                }
+                (true, false) => {
+                    self.ducked.insert(bus_name);
+                }
+            }
+        }
+        !self.ramps.is_empty()
+    }
+}
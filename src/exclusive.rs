@@ -0,0 +1,101 @@
+use log::{debug, info, warn};
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use mpris::PlaybackStatus;
+
+use crate::selection::{FoundPlayer, PlayerPattern};
+
+/// A player paused because others started playing
+struct Interruption {
+    bus_name: String,
+    /// Players that started playing, resume once none of them plays anymore
+    interrupters: HashSet<String>,
+}
+
+/// Pauses players that were already playing when another one starts
+#[derive(Default)]
+pub struct ExclusivePlayback {
+    /// Bus names of players playing at the last check, None before the first one
+    playing: Option<HashSet<String>>,
+    interruptions: Vec<Interruption>,
+}
+
+impl ExclusivePlayback {
+    /// Pause interrupted players, and resume them after the interruption if resume is set,
+    /// rewinding by the given duration; players matching ducking patterns do not interrupt others
+    pub fn check(&mut self, players: &[FoundPlayer], exempt: &[PlayerPattern], ducking: &[PlayerPattern], resume: Option<Duration>) {
+        let playing: Vec<&FoundPlayer> = players.iter()
+            .filter(|found| found.status == Some(PlaybackStatus::Playing))
+            .collect();
+        let mut bus_names: HashSet<String> = playing.iter().map(|found| found.player.bus_name().to_string()).collect();
+        // Resumed players did not just start, so they must not interrupt others
+        match resume {
+            Some(rewind) => bus_names.extend(self.resume_interrupted(players, &bus_names, rewind)),
+            None => self.interruptions.clear(),
+        }
+        // Players already playing on startup are left alone
+        let Some(was_playing) = self.playing.replace(bus_names) else {
+            return;
+        };
+        let started: HashSet<String> = playing.iter()
+            .filter(|found| !was_playing.contains(found.player.bus_name()))
+            .filter(|found| !ducking.iter().any(|pattern| pattern.matches(*found)))
+            .map(|found| found.player.bus_name().to_string())
+            .collect();
+        if started.is_empty() {
+            return;
+        }
+        for found in playing.iter().filter(|found| was_playing.contains(found.player.bus_name())) {
+            if exempt.iter().any(|pattern| pattern.matches(*found)) {
+                continue;
+            }
+            let player = &found.player;
+            info!("Pausing {} since another player started", player.bus_name());
+            match player.pause() {
+                Ok(()) => {
+                    if let Some(playing) = &mut self.playing {
+                        playing.remove(player.bus_name());
+                    }
+                    self.interruptions.push(Interruption {
+                        bus_name: player.bus_name().to_string(),
+                        interrupters: started.clone(),
+                    });
+                }
+                Err(e) => warn!("Failed to pause {}: {e}", player.bus_name()),
+            }
+        }
+    }
+
+    /// Resume players whose interrupters all stopped playing or disappeared, returns the resumed ones
+    fn resume_interrupted(&mut self, players: &[FoundPlayer], playing: &HashSet<String>, rewind: Duration) -> Vec<String> {
+        let (ended, ongoing) = std::mem::take(&mut self.interruptions)
+            .into_iter()
+            .partition(|interruption: &Interruption| interruption.interrupters.is_disjoint(playing));
+        self.interruptions = ongoing;
+        let mut resumed = Vec::new();
+        for interruption in ended {
+            let Some(found) = players.iter().find(|found| found.player.bus_name() == interruption.bus_name) else {
+                debug!("Interrupted player {} is gone", interruption.bus_name);
+                continue;
+            };
+            // Someone else resumed or stopped it in the meantime
+            if found.status != Some(PlaybackStatus::Paused) {
+                continue;
+            }
+            let player = &found.player;
+            info!("Resuming {} after interruption", interruption.bus_name);
+            if !rewind.is_zero() {
+                if let Err(e) = player.seek_backwards(&rewind) {
+                    warn!("Failed to rewind {}: {e}", interruption.bus_name);
+                }
+            }
+            match player.play() {
+                Ok(()) => resumed.push(interruption.bus_name),
+                Err(e) => warn!("Failed to resume {}: {e}", interruption.bus_name),
+            }
+        }
+        resumed
+    }
+}
@@ -0,0 +1,357 @@
+use log::{debug, info, warn};
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::PlayerState;
+
+/// Tracks that ended before this share of their duration was listened to count as skipped
+const SKIP_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub player_bus_name: String,
+    pub player_identity: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub url: Option<String>,
+    pub duration: Option<f32>,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+    /// Unix timestamp in seconds
+    pub ended_at: u64,
+    /// Seconds the track was actually playing
+    pub listened: f32,
+    pub skipped: bool,
+}
+
+/// Library-like statistics derived from the distinct tracks in the history
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryStats {
+    pub songs: usize,
+    pub artists: usize,
+    pub albums: usize,
+    /// Summed up duration of all distinct tracks, in seconds
+    pub db_playtime: u64,
+    /// Unix timestamp of the most recent entry
+    pub db_update: u64,
+}
+
+struct CurrentTrack {
+    entry: HistoryEntry,
+    playing: bool,
+    last_update: Instant,
+}
+
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+    current: Option<CurrentTrack>,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn default_history_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("mpd-mpris-bridge").join("history.jsonl"))
+}
+
+fn is_same_track(entry: &HistoryEntry, player_bus_name: &str, state: &PlayerState) -> bool {
+    entry.player_bus_name == player_bus_name &&
+        entry.title == state.title &&
+        entry.artist == state.artist &&
+        entry.album == state.album &&
+        entry.url == state.url
+}
+
+impl History {
+    /// History that is only kept in memory
+    pub fn in_memory() -> History {
+        History {
+            path: None,
+            entries: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Load the history from a JSON lines file, which does not need to exist yet
+    pub fn load(path: &Path) -> anyhow::Result<History> {
+        let mut entries = Vec::new();
+        match File::open(path) {
+            Ok(file) => {
+                for (i, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(&line) {
+                        Ok(entry) => entries.push(entry),
+                        Err(e) => warn!("Ignoring invalid history entry in {}:{}: {e}", path.display(), i + 1),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No history at {} yet", path.display());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        info!("Loaded {} history entries from {}", entries.len(), path.display());
+        Ok(History {
+            path: Some(path.to_path_buf()),
+            entries,
+            current: None,
+        })
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// Feed the latest state of the bridged player, or None if no player is bridged.
+    /// Only the bridged player is recorded, other players do not show up in the history.
+    pub fn update(&mut self, player: Option<(&str, &str)>, state: Option<&PlayerState>) {
+        self.update_at(Instant::now(), player, state);
+    }
+
+    fn update_at(&mut self, now: Instant, player: Option<(&str, &str)>, state: Option<&PlayerState>) {
+        if let Some(current) = self.current.as_mut() {
+            if current.playing {
+                current.entry.listened += now.duration_since(current.last_update).as_secs_f32();
+            }
+            current.last_update = now;
+        }
+        let (Some((bus_name, identity)), Some(state)) = (player, state) else {
+            self.finish_current();
+            return;
+        };
+        let same_track = self.current.as_ref()
+            .map(|current| is_same_track(&current.entry, bus_name, state))
+            .unwrap_or(false);
+        if !same_track {
+            self.finish_current();
+            if state.title.is_none() && state.url.is_none() {
+                return;
+            }
+            let started_at = unix_now();
+            self.current = Some(CurrentTrack {
+                entry: HistoryEntry {
+                    player_bus_name: bus_name.to_string(),
+                    player_identity: identity.to_string(),
+                    title: state.title.clone(),
+                    artist: state.artist.clone(),
+                    album: state.album.clone(),
+                    url: state.url.clone(),
+                    duration: state.duration,
+                    started_at,
+                    ended_at: started_at,
+                    listened: 0.0,
+                    skipped: false,
+                },
+                playing: false,
+                last_update: now,
+            });
+        }
+        if let Some(current) = self.current.as_mut() {
+            current.playing = state.playback_status == mpris::PlaybackStatus::Playing;
+            // Some players only report the duration a bit after the track change
+            if current.entry.duration.is_none() {
+                current.entry.duration = state.duration;
+            }
+        }
+    }
+
+    /// Close the currently tracked entry, e.g. on track change or shutdown
+    pub fn finish_current(&mut self) {
+        let Some(current) = self.current.take() else {
+            return;
+        };
+        let mut entry = current.entry;
+        if entry.listened <= 0.0 {
+            debug!("Not recording never played track {:?}", entry.title);
+            return;
+        }
+        entry.ended_at = unix_now();
+        entry.skipped = entry.duration
+            .map(|duration| entry.listened < duration * SKIP_THRESHOLD)
+            .unwrap_or(false);
+        debug!("Recording history entry {entry:?}");
+        if let Err(e) = self.persist(&entry) {
+            warn!("Failed to persist history entry: {e}");
+        }
+        self.entries.push(entry);
+    }
+
+    fn persist(&self, entry: &HistoryEntry) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Option<HistoryStats> {
+        let last = self.entries.last()?;
+        let mut songs = HashSet::new();
+        let mut artists = HashSet::new();
+        let mut albums = HashSet::new();
+        let mut db_playtime = 0.0;
+        for entry in &self.entries {
+            if songs.insert((&entry.title, &entry.artist, &entry.album, &entry.url)) {
+                db_playtime += entry.duration.unwrap_or(0.0);
+            }
+            if let Some(artist) = &entry.artist {
+                artists.insert(artist);
+            }
+            if let Some(album) = &entry.album {
+                albums.insert(album);
+            }
+        }
+        Some(HistoryStats {
+            songs: songs.len(),
+            artists: artists.len(),
+            albums: albums.len(),
+            db_playtime: db_playtime as u64,
+            db_update: last.ended_at,
+        })
+    }
+
+    pub fn export_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&self.entries)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mpris::PlaybackStatus;
+
+    use super::*;
+
+    const PLAYER: Option<(&str, &str)> = Some(("org.mpris.MediaPlayer2.vlc", "VLC media player"));
+
+    fn track(title: &str, duration: Option<f32>, playback_status: PlaybackStatus) -> PlayerState {
+        PlayerState {
+            playback_status,
+            title: Some(title.to_string()),
+            artist: Some("Artist".to_string()),
+            duration,
+            elapsed: None,
+            elapsed_at: None,
+            art_url: None,
+            album: None,
+            url: None,
+        }
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn track_change_finishes_the_entry() {
+        let mut history = History::in_memory();
+        let start = Instant::now();
+        let first = track("First", Some(100.0), PlaybackStatus::Playing);
+        history.update_at(start, PLAYER, Some(&first));
+        history.update_at(secs(start, 30), PLAYER, Some(&first));
+        assert!(history.entries().is_empty());
+        history.update_at(secs(start, 80), PLAYER, Some(&track("Second", Some(100.0), PlaybackStatus::Playing)));
+        let [entry] = history.entries() else {
+            panic!("Expected one entry, got {:?}", history.entries());
+        };
+        assert_eq!(entry.title.as_deref(), Some("First"));
+        assert_eq!(entry.player_bus_name, "org.mpris.MediaPlayer2.vlc");
+        assert_eq!(entry.player_identity, "VLC media player");
+        assert_eq!(entry.listened, 80.0);
+        assert!(!entry.skipped);
+    }
+
+    #[test]
+    fn short_listens_are_skips() {
+        let mut history = History::in_memory();
+        let start = Instant::now();
+        history.update_at(start, PLAYER, Some(&track("First", Some(100.0), PlaybackStatus::Playing)));
+        history.update_at(secs(start, 49), PLAYER, Some(&track("Second", None, PlaybackStatus::Playing)));
+        // Without a duration a track cannot count as skipped
+        history.update_at(secs(start, 50), PLAYER, Some(&track("Third", Some(100.0), PlaybackStatus::Playing)));
+        let skipped: Vec<bool> = history.entries().iter().map(|entry| entry.skipped).collect();
+        assert_eq!(skipped, [true, false]);
+    }
+
+    #[test]
+    fn paused_time_does_not_count() {
+        let mut history = History::in_memory();
+        let start = Instant::now();
+        history.update_at(start, PLAYER, Some(&track("First", Some(100.0), PlaybackStatus::Playing)));
+        history.update_at(secs(start, 20), PLAYER, Some(&track("First", Some(100.0), PlaybackStatus::Paused)));
+        history.update_at(secs(start, 500), PLAYER, Some(&track("First", Some(100.0), PlaybackStatus::Playing)));
+        history.update_at(secs(start, 520), PLAYER, Some(&track("Second", Some(100.0), PlaybackStatus::Playing)));
+        let [entry] = history.entries() else {
+            panic!("Expected one entry, got {:?}", history.entries());
+        };
+        assert_eq!(entry.listened, 40.0);
+        assert!(entry.skipped);
+    }
+
+    #[test]
+    fn duration_reported_late_is_kept() {
+        let mut history = History::in_memory();
+        let start = Instant::now();
+        history.update_at(start, PLAYER, Some(&track("First", None, PlaybackStatus::Playing)));
+        history.update_at(secs(start, 1), PLAYER, Some(&track("First", Some(100.0), PlaybackStatus::Playing)));
+        history.update_at(secs(start, 10), None, None);
+        let [entry] = history.entries() else {
+            panic!("Expected one entry, got {:?}", history.entries());
+        };
+        assert_eq!(entry.duration, Some(100.0));
+        assert!(entry.skipped);
+    }
+
+    #[test]
+    fn losing_the_player_finishes_the_entry() {
+        let mut history = History::in_memory();
+        let start = Instant::now();
+        let first = track("First", Some(100.0), PlaybackStatus::Playing);
+        history.update_at(start, PLAYER, Some(&first));
+        history.update_at(secs(start, 60), None, None);
+        assert_eq!(history.entries().len(), 1);
+        assert_eq!(history.entries()[0].listened, 60.0);
+        // Coming back starts a new entry for the same track
+        history.update_at(secs(start, 70), PLAYER, Some(&first));
+        history.update_at(secs(start, 80), Some(("org.mpris.MediaPlayer2.mpv", "mpv")), Some(&first));
+        assert_eq!(history.entries().len(), 2);
+        assert_eq!(history.entries()[1].listened, 10.0);
+    }
+
+    #[test]
+    fn unplayed_and_untitled_tracks_are_not_recorded() {
+        let mut history = History::in_memory();
+        let start = Instant::now();
+        history.update_at(start, PLAYER, Some(&track("First", Some(100.0), PlaybackStatus::Paused)));
+        history.update_at(secs(start, 60), PLAYER, Some(&track("Second", Some(100.0), PlaybackStatus::Playing)));
+        assert!(history.entries().is_empty());
+        let mut untitled = track("", Some(100.0), PlaybackStatus::Playing);
+        untitled.title = None;
+        history.update_at(secs(start, 60), PLAYER, Some(&untitled));
+        history.update_at(secs(start, 120), PLAYER, Some(&untitled));
+        history.finish_current();
+        assert!(history.entries().is_empty());
+    }
+}
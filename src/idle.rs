@@ -0,0 +1,236 @@
+use log::debug;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::Notify;
+
+/// Set of subsystems clients can wait for with idle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Subsystems(u16);
+
+impl Subsystems {
+    pub const NONE: Subsystems = Subsystems(0);
+    pub const PLAYER: Subsystems = Subsystems(1);
+    pub const PLAYLIST: Subsystems = Subsystems(1 << 1);
+    pub const MIXER: Subsystems = Subsystems(1 << 2);
+    pub const STICKER: Subsystems = Subsystems(1 << 3);
+    pub const SUBSCRIPTION: Subsystems = Subsystems(1 << 4);
+    pub const MESSAGE: Subsystems = Subsystems(1 << 5);
+    pub const OUTPUT: Subsystems = Subsystems(1 << 6);
+    pub const PARTITION: Subsystems = Subsystems(1 << 7);
+    pub const ALL: Subsystems = Subsystems((1 << 8) - 1);
+
+    const NAMES: &'static [(&'static str, Subsystems)] = &[
+        ("player", Subsystems::PLAYER),
+        ("playlist", Subsystems::PLAYLIST),
+        ("mixer", Subsystems::MIXER),
+        ("sticker", Subsystems::STICKER),
+        ("subscription", Subsystems::SUBSCRIPTION),
+        ("message", Subsystems::MESSAGE),
+        ("output", Subsystems::OUTPUT),
+        ("partition", Subsystems::PARTITION),
+    ];
+
+    /// Subsystems named in idle arguments, all of them if there are no arguments
+    pub fn from_idle_arguments(arguments: &[String]) -> Subsystems {
+        if arguments.is_empty() {
+            return Subsystems::ALL;
+        }
+        Subsystems::NAMES.iter()
+            .filter(|(name, _)| arguments.iter().any(|argument| argument == name))
+            .fold(Subsystems::NONE, |subsystems, (_, subsystem)| subsystems | *subsystem)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Subsystems::NAMES.iter()
+            .filter(move |(_, subsystem)| self.0 & subsystem.0 != 0)
+            .map(|(name, _)| *name)
+    }
+}
+
+impl std::ops::BitOr for Subsystems {
+    type Output = Subsystems;
+
+    fn bitor(self, other: Subsystems) -> Subsystems {
+        Subsystems(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Subsystems {
+    fn bitor_assign(&mut self, other: Subsystems) {
+        self.0 |= other.0;
+    }
+}
+
+impl std::ops::BitAnd for Subsystems {
+    type Output = Subsystems;
+
+    fn bitand(self, other: Subsystems) -> Subsystems {
+        Subsystems(self.0 & other.0)
+    }
+}
+
+impl std::fmt::Display for Subsystems {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.names().collect::<Vec<_>>().join(","))
+    }
+}
+
+/// Connections a change is relevant for
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdleScope {
+    All,
+    /// Connections in this partition, None for the default partition
+    Partition(Option<String>),
+    Clients(Vec<u64>),
+}
+
+/// Announces changes to the connections they are relevant for, so idle does not need to poll
+#[derive(Default)]
+pub struct IdleRegistry {
+    clients: Mutex<HashMap<u64, Arc<IdleClient>>>,
+}
+
+impl IdleRegistry {
+    pub fn new() -> IdleRegistry {
+        IdleRegistry::default()
+    }
+
+    pub fn notify(&self, subsystems: Subsystems, scope: IdleScope) {
+        debug!("Idle event {subsystems} for {scope:?}");
+        let clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        for (client_id, client) in clients.iter() {
+            let mut state = client.lock();
+            let relevant = match &scope {
+                IdleScope::All => true,
+                IdleScope::Partition(partition) => *partition == state.partition,
+                IdleScope::Clients(ids) => ids.contains(client_id),
+            };
+            if relevant {
+                state.pending |= subsystems;
+                client.notify.notify_one();
+            }
+        }
+    }
+
+    pub fn register(&self, client_id: u64) -> Arc<IdleClient> {
+        let client = Arc::new(IdleClient::default());
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner).insert(client_id, client.clone());
+        client
+    }
+
+    pub fn unregister(&self, client_id: u64) {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner).remove(&client_id);
+    }
+}
+
+#[derive(Default)]
+struct IdleClientState {
+    pending: Subsystems,
+    /// None for the default partition
+    partition: Option<String>,
+}
+
+/// Changes a connection has not been told about yet, collected per client like MPD does
+#[derive(Default)]
+pub struct IdleClient {
+    state: Mutex<IdleClientState>,
+    notify: Notify,
+}
+
+impl IdleClient {
+    fn lock(&self) -> std::sync::MutexGuard<'_, IdleClientState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Partition whose changes the connection is told about
+    pub fn set_partition(&self, partition: Option<String>) {
+        self.lock().partition = partition;
+    }
+
+    /// Wait until changes may be pending, including those made before the call
+    pub async fn wait(&self) {
+        self.notify.notified().await;
+    }
+
+    /// Report changes only this connection sees, like those of switching partitions
+    pub fn mark(&self, subsystems: Subsystems) {
+        self.lock().pending |= subsystems;
+    }
+
+    /// Pending changes of the given subsystems, which are no longer pending afterwards
+    pub fn take(&self, subsystems: Subsystems) -> Subsystems {
+        let mut state = self.lock();
+        let changed = state.pending & subsystems;
+        state.pending = Subsystems(state.pending.0 & !subsystems.0);
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> Subsystems {
+        Subsystems::from_idle_arguments(&arguments.iter().map(|argument| argument.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn idle_arguments() {
+        assert_eq!(arguments(&[]), Subsystems::ALL);
+        assert_eq!(arguments(&["player", "mixer"]), Subsystems::PLAYER | Subsystems::MIXER);
+        // Subsystems we do not know are not mistaken for ones with a similar name
+        assert_eq!(arguments(&["stored_playlist"]), Subsystems::NONE);
+        assert_eq!(arguments(&["stored_playlist", "playlist"]), Subsystems::PLAYLIST);
+        assert_eq!(arguments(&["players"]), Subsystems::NONE);
+        assert_eq!(Subsystems::ALL.to_string(), "player,playlist,mixer,sticker,subscription,message,output,partition");
+    }
+
+    #[test]
+    fn take_and_mark() {
+        let client = IdleClient::default();
+        client.mark(Subsystems::PLAYER | Subsystems::MIXER);
+        assert_eq!(client.take(Subsystems::PLAYER | Subsystems::OUTPUT), Subsystems::PLAYER);
+        assert_eq!(client.take(Subsystems::PLAYER), Subsystems::NONE);
+        assert_eq!(client.take(Subsystems::ALL), Subsystems::MIXER);
+        assert_eq!(client.take(Subsystems::ALL), Subsystems::NONE);
+    }
+
+    #[test]
+    fn notify_reaches_clients_in_scope() {
+        let registry = IdleRegistry::new();
+        let default = registry.register(1);
+        let bound = registry.register(2);
+        bound.set_partition(Some("vlc".to_string()));
+        registry.notify(Subsystems::PLAYER, IdleScope::Partition(None));
+        registry.notify(Subsystems::OUTPUT, IdleScope::Partition(Some("vlc".to_string())));
+        registry.notify(Subsystems::MESSAGE, IdleScope::Clients(vec![2]));
+        registry.notify(Subsystems::SUBSCRIPTION, IdleScope::All);
+        assert_eq!(default.take(Subsystems::ALL), Subsystems::PLAYER | Subsystems::SUBSCRIPTION);
+        assert_eq!(bound.take(Subsystems::ALL), Subsystems::OUTPUT | Subsystems::MESSAGE | Subsystems::SUBSCRIPTION);
+        // Unregistered clients are no longer told about changes
+        registry.unregister(1);
+        registry.notify(Subsystems::MIXER, IdleScope::All);
+        assert_eq!(default.take(Subsystems::ALL), Subsystems::NONE);
+        assert_eq!(bound.take(Subsystems::ALL), Subsystems::MIXER);
+    }
+
+    #[tokio::test]
+    async fn wait_returns_for_earlier_changes() {
+        let registry = IdleRegistry::new();
+        let client = registry.register(1);
+        registry.notify(Subsystems::PLAYER, IdleScope::Partition(Some("vlc".to_string())));
+        assert!(tokio::time::timeout(Duration::from_millis(50), client.wait()).await.is_err());
+        // Changes between taking the pending ones and waiting still wake up
+        registry.notify(Subsystems::PLAYER, IdleScope::All);
+        tokio::time::timeout(Duration::from_secs(1), client.wait()).await.unwrap();
+        assert_eq!(client.take(Subsystems::ALL), Subsystems::PLAYER);
+    }
+}
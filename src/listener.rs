@@ -0,0 +1,209 @@
+use std::path::PathBuf;
+
+use crate::access::AccessControl;
+use crate::permissions::Permissions;
+use crate::selection::PlayerPattern;
+
+/// Protocol version announced in the greeting unless configured otherwise
+pub const PROTOCOL_VERSION: &str = "0.23.16";
+
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    /// Anything TcpListener::bind resolves, like "localhost:6600" or "[::]:6600"
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// How song metadata is formatted for clients
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MetadataStyle {
+    #[default]
+    Standard,
+    /// The title as file, like before songs had stable URIs
+    Legacy,
+}
+
+impl std::str::FromStr for MetadataStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<MetadataStyle> {
+        match value {
+            "standard" => Ok(MetadataStyle::Standard),
+            "legacy" => Ok(MetadataStyle::Legacy),
+            _ => Err(anyhow::anyhow!("Unknown metadata style {value}, expected standard or legacy")),
+        }
+    }
+}
+
+/// Settings that apply to all connections of one listener
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListenerProfile {
+    /// Overrides the global default permissions
+    pub default_permissions: Option<Permissions>,
+    /// Never grant more than read permissions, not even with a password
+    pub read_only: bool,
+    /// Bind connections to the partition of the first player matching this, without allowing to switch
+    pub player: Option<PlayerPattern>,
+    pub protocol_version: Option<String>,
+    pub metadata_style: MetadataStyle,
+    /// Grant all permissions to Unix socket peers running as the same user as the bridge
+    pub trust_owner: bool,
+    /// File mode for Unix sockets
+    pub socket_mode: Option<u32>,
+    /// Allow and deny rules for TCP connections on top of the global ones
+    pub access: AccessControl,
+}
+
+impl ListenerProfile {
+    pub fn permission_limit(&self) -> Permissions {
+        match self.read_only {
+            true => Permissions::READ,
+            false => Permissions::ALL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    pub address: ListenAddress,
+    pub profile: ListenerProfile,
+}
+
+pub fn default_socket_path() -> Option<PathBuf> {
+    dirs::runtime_dir().map(|dir| dir.join("mpd").join("socket"))
+}
+
+pub fn parse_socket_mode(value: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(value.trim_start_matches("0o"), 8)
+        .map_err(|_| anyhow::anyhow!("Expected an octal file mode like 660"))?;
+    if mode > 0o777 {
+        return Err(anyhow::anyhow!("File mode {value} out of range"));
+    }
+    Ok(mode)
+}
+
+pub fn parse_protocol_version(value: &str) -> anyhow::Result<String> {
+    let parts: Vec<&str> = value.split('.').collect();
+    if parts.len() != 3 || parts.iter().any(|part| part.parse::<u16>().is_err()) {
+        return Err(anyhow::anyhow!("Expected a protocol version like 0.23.16, got {value}"));
+    }
+    Ok(value.to_string())
+}
+
+impl std::str::FromStr for ListenerConfig {
+    type Err = anyhow::Error;
+
+    /// Parse ADDRESS[,OPTION...], where ADDRESS is HOST:PORT or unix:PATH
+    fn from_str(value: &str) -> anyhow::Result<ListenerConfig> {
+        let mut parts = value.split(',');
+        let address = parts.next().unwrap_or("");
+        let address = match address.strip_prefix("unix:") {
+            Some("") => return Err(anyhow::anyhow!("Empty socket path")),
+            Some(path) => ListenAddress::Unix(PathBuf::from(path)),
+            None => {
+                let Some((_, port)) = address.rsplit_once(':') else {
+                    return Err(anyhow::anyhow!("Expected HOST:PORT or unix:PATH, got {address}"));
+                };
+                port.parse::<u16>().map_err(|_| anyhow::anyhow!("Invalid port {port}"))?;
+                ListenAddress::Tcp(address.to_string())
+            }
+        };
+        let is_unix = matches!(address, ListenAddress::Unix(_));
+        let mut profile = ListenerProfile::default();
+        for option in parts.map(|option| option.trim()).filter(|option| !option.is_empty()) {
+            let (key, option_value) = match option.split_once('=') {
+                Some((key, option_value)) => (key, Some(option_value)),
+                None => (option, None),
+            };
+            match (key, option_value) {
+                // Permission lists are joined with '+' here, since ',' separates options
+                ("permissions", Some(v)) => profile.default_permissions = Some(Permissions::parse(&v.replace('+', ","))?),
+                ("read-only", None) => profile.read_only = true,
+                ("player", Some(v)) => profile.player = Some(v.parse()?),
+                ("version", Some(v)) => profile.protocol_version = Some(parse_protocol_version(v)?),
+                ("metadata", Some(v)) => profile.metadata_style = v.parse()?,
+                ("allow", Some(v)) if !is_unix => profile.access.allow.push(v.replace('+', ",").parse()?),
+                ("deny", Some(v)) if !is_unix => profile.access.deny.push(v.parse()?),
+                ("trust-owner", None) if is_unix => profile.trust_owner = true,
+                ("mode", Some(v)) if is_unix => profile.socket_mode = Some(parse_socket_mode(v)?),
+                _ => return Err(anyhow::anyhow!("Invalid listener option {option}")),
+            }
+        }
+        Ok(ListenerConfig { address, profile })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> ListenerConfig {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn plain_addresses() {
+        assert_eq!(parse("localhost:6600"), ListenerConfig {
+            address: ListenAddress::Tcp("localhost:6600".to_string()),
+            profile: ListenerProfile::default(),
+        });
+        assert_eq!(parse("[::]:6601").address, ListenAddress::Tcp("[::]:6601".to_string()));
+        assert_eq!(parse("unix:/run/mpd/socket").address, ListenAddress::Unix(PathBuf::from("/run/mpd/socket")));
+    }
+
+    #[test]
+    fn tcp_options() {
+        let config = parse("0.0.0.0:6600,permissions=read+control,read-only,player=vlc,version=0.21.0,metadata=legacy");
+        assert_eq!(config.profile, ListenerProfile {
+            default_permissions: Some(Permissions::READ | Permissions::CONTROL),
+            read_only: true,
+            player: Some("vlc".parse().unwrap()),
+            protocol_version: Some("0.21.0".to_string()),
+            metadata_style: MetadataStyle::Legacy,
+            ..ListenerProfile::default()
+        });
+        assert_eq!(config.profile.permission_limit(), Permissions::READ);
+    }
+
+    #[test]
+    fn access_options() {
+        let config = parse("0.0.0.0:6600,allow=10.0.0.0/8=read+add,allow=127.0.0.1,deny=10.0.0.1");
+        assert_eq!(config.profile.access, AccessControl {
+            allow: vec!["10.0.0.0/8=read,add".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+            deny: vec!["10.0.0.1".parse().unwrap()],
+        });
+    }
+
+    #[test]
+    fn unix_options() {
+        let config = parse("unix:/tmp/mpd.sock,trust-owner,mode=0o660, player=re:^spot");
+        assert!(config.profile.trust_owner);
+        assert_eq!(config.profile.socket_mode, Some(0o660));
+        assert_eq!(config.profile.player, Some("re:^spot".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_listeners() {
+        for value in [
+            "",
+            "localhost",
+            "localhost:http",
+            "unix:",
+            "localhost:6600,unknown",
+            "localhost:6600,read-only=yes",
+            "localhost:6600,permissions=write",
+            "localhost:6600,version=1.2",
+            "localhost:6600,metadata=fancy",
+            "localhost:6600,trust-owner",
+            "localhost:6600,mode=600",
+            "unix:/tmp/mpd.sock,allow=127.0.0.1",
+            "unix:/tmp/mpd.sock,deny=127.0.0.1",
+            "unix:/tmp/mpd.sock,mode=999",
+            "unix:/tmp/mpd.sock,player=",
+        ] {
+            assert!(value.parse::<ListenerConfig>().is_err(), "{value} should be rejected");
+        }
+    }
+}
@@ -1,29 +1,164 @@
+mod access;
+mod channels;
+mod config;
+mod ducking;
+mod exclusive;
+mod history;
+mod idle;
+mod listener;
+mod permissions;
+mod selection;
+mod signals;
+mod sticker;
+mod systemd;
+
 use log::{trace, debug, info, warn, error};
 
-use std::sync::atomic::{AtomicU8, AtomicBool};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, AtomicU64, AtomicBool};
 use std::sync::atomic::Ordering;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::mpsc;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::time::{sleep, timeout};
 
 use mpris::{PlayerFinder, Player};
 
+use access::{AllowRule, DenyRule};
+use channels::{ChannelError, ChannelRegistry};
+use config::Settings;
+use ducking::Ducking;
+use exclusive::ExclusivePlayback;
+use history::History;
+use idle::{IdleClient, IdleRegistry, IdleScope, Subsystems};
+use listener::{ListenAddress, ListenerConfig, ListenerProfile, MetadataStyle};
+use permissions::{PasswordEntry, Permissions};
+use selection::{FoundPlayer, PlayerInfo, PlayerPattern, SelectionPolicy, SwitchMode, SwitchState};
+use signals::{BridgedChanges, PendingChanges};
+use sticker::StickerDb;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value_t = 6601)]
-    port: u16,
-    #[arg(short, long, default_value_t = String::from("0.0.0.0"))]
-    bind_address: String,
+    /// Config file with the same keys as the long options [default: $XDG_CONFIG_HOME/mpd-mpris-bridge/config.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// [default: 6601]
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// [default: 0.0.0.0]
+    #[arg(short, long)]
+    bind_address: Option<String>,
+    /// Where to persist the listening history of the bridged player [default: $XDG_DATA_HOME/mpd-mpris-bridge/history.jsonl]
+    #[arg(long)]
+    history_file: Option<PathBuf>,
+    /// Only keep the listening history in memory
+    #[arg(long)]
+    no_history: bool,
+    /// Where to persist stickers [default: $XDG_DATA_HOME/mpd-mpris-bridge/stickers.json]
+    #[arg(long)]
+    sticker_file: Option<PathBuf>,
+    /// Only keep stickers in memory
+    #[arg(long)]
+    no_sticker_file: bool,
+    /// Where to remember volumes of ducked players [default: $XDG_DATA_HOME/mpd-mpris-bridge/volumes.json]
+    #[arg(long)]
+    volume_file: Option<PathBuf>,
+    /// Only keep volumes of ducked players in memory
+    #[arg(long)]
+    no_volume_file: bool,
+    /// Password granting permissions, as PASSWORD@read,add,control,admin (can be repeated)
+    #[arg(long = "password")]
+    passwords: Vec<PasswordEntry>,
+    /// Permissions of connections without password [default: all if no password is configured, none otherwise]
+    #[arg(long, value_parser = Permissions::parse)]
+    default_permissions: Option<Permissions>,
+    /// Only accept connections from this IP or CIDR range, optionally with own default permissions
+    /// as RANGE=read,add,control,admin (can be repeated)
+    #[arg(long = "allow")]
+    allow_rules: Vec<AllowRule>,
+    /// Reject connections from this IP or CIDR range (can be repeated)
+    #[arg(long = "deny")]
+    deny_rules: Vec<DenyRule>,
+    /// Listen on ADDRESS[,OPTION...] instead of --bind-address and --port (can be repeated).
+    /// ADDRESS is HOST:PORT or unix:PATH, options are permissions=read+control, read-only,
+    /// player=PATTERN, version=0.23.16, metadata=standard|legacy, for TCP allow=RANGE[=read+control] and
+    /// deny=RANGE (can be repeated, allow replaces --allow) and for Unix sockets trust-owner, mode=660
+    #[arg(long = "listen", value_name = "SPEC")]
+    listeners: Vec<ListenerConfig>,
+    /// Also listen on a Unix socket [default path: $XDG_RUNTIME_DIR/mpd/socket]
+    #[arg(long, value_name = "PATH")]
+    socket: Option<Option<PathBuf>>,
+    /// File mode of the Unix socket, in octal [default: 600]
+    #[arg(long, value_parser = listener::parse_socket_mode)]
+    socket_mode: Option<u32>,
+    /// Grant all permissions to Unix socket connections from the user running the bridge
+    #[arg(long)]
+    socket_trust_owner: bool,
+    /// Do not listen on --bind-address and --port, e.g. to only accept local clients on the Unix socket
+    #[arg(long)]
+    no_tcp: bool,
+    /// Seconds to wait for clients and pending player commands when shutting down [default: 5]
+    #[arg(long)]
+    shutdown_timeout: Option<u64>,
+    /// Only bridge players whose bus name or Identity matches this glob, or regex as re:REGEX (can be repeated)
+    #[arg(long = "player-allow", value_name = "PATTERN")]
+    player_allow: Vec<PlayerPattern>,
+    /// Never bridge players matching this pattern automatically (can be repeated)
+    #[arg(long = "player-deny", value_name = "PATTERN")]
+    player_deny: Vec<PlayerPattern>,
+    /// Prefer players matching this pattern over later ones and unmatched players (can be repeated)
+    #[arg(long = "player-priority", value_name = "PATTERN")]
+    player_priority: Vec<PlayerPattern>,
+    /// When to switch to another player: pinned, playing or follow [default: playing]
+    #[arg(long, value_name = "MODE")]
+    switch_mode: Option<SwitchMode>,
+    /// Seconds another player must have been playing before switching to it [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    switch_delay: Option<u64>,
+    /// Pause other players when one starts playing
+    #[arg(long)]
+    exclusive_playback: bool,
+    /// Never pause players matching this pattern for exclusive playback (can be repeated)
+    #[arg(long = "exclusive-exempt", value_name = "PATTERN")]
+    exclusive_exempt: Vec<PlayerPattern>,
+    /// Resume players paused for exclusive playback once the interrupting player stops (implies --exclusive-playback)
+    #[arg(long)]
+    auto_resume: bool,
+    /// Seconds to rewind players when resuming them after an interruption [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    resume_rewind: Option<u64>,
+    /// Lower the volume of other players while a player matching this pattern plays, instead of pausing them (can be repeated)
+    #[arg(long = "duck-foreground", value_name = "PATTERN")]
+    duck_foreground: Vec<PlayerPattern>,
+    /// Volume of ducked players, in percent of their original volume [default: 20]
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    duck_volume: Option<u8>,
+    /// Milliseconds to ramp volumes when ducking and restoring [default: 500]
+    #[arg(long, value_name = "MS")]
+    duck_ramp_ms: Option<u64>,
+    /// Seconds to keep playback commands while no player is connected, to run them once one appears;
+    /// 0 rejects them right away [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    command_ttl: Option<u64>,
+    /// Print the persisted listening history as JSON and exit
+    #[arg(long)]
+    export_history: bool,
 }
 
-#[derive(Debug)]
+/// Stream an MPD client is connected through
+trait MpdSocket: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> MpdSocket for T {}
+
+#[derive(Debug, Clone, Copy)]
 enum Command {
     Play,
     Pause,
@@ -32,27 +167,167 @@ enum Command {
     Prev,
 }
 
+#[derive(Debug)]
+struct PlayerCommand {
+    /// Partition of the player to control, None for the bridged player
+    partition: Option<String>,
+    command: Command,
+    /// Where to report whether the player accepted the command, None if nobody waits for it
+    reply: Option<oneshot::Sender<anyhow::Result<()>>>,
+}
+
+/// Partition that follows the bridged player
+const DEFAULT_PARTITION: &str = "default";
+
+/// MPRIS players are exposed as partitions named after the last part of their bus name
+fn partition_name(bus_name: &str) -> &str {
+    bus_name.strip_prefix("org.mpris.MediaPlayer2.").unwrap_or(bus_name)
+}
+
+/// Channel on which MPD clients can send commands to the bridge itself
+const BRIDGE_CHANNEL: &str = "mpd-mpris-bridge";
+
+/// How long to wait before accepting again after a failed accept, e.g. when out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long the player observer may take to clean up when clients did not disconnect in time
+const OBSERVER_STOP_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+enum BridgeCommand {
+    ListPlayers,
+    SelectPlayer(String),
+    ReleasePlayer,
+    /// Exclude a player from being bridged until it is selected again
+    DisablePlayer(String),
+    Reload,
+}
+
+/// An MPRIS player as exposed via the MPD outputs commands
+#[derive(Debug, Clone, PartialEq)]
+struct OutputInfo {
+    id: u32,
+    bus_name: String,
+    identity: String,
+    /// Whether this is the currently bridged player
+    enabled: bool,
+}
+
+/// How the observer decides which MPRIS player to bridge
+#[derive(Default)]
+struct PlayerSelection {
+    /// Bus name of the player explicitly selected by the user
+    pinned: Option<String>,
+    /// Bus names of players that should not be selected automatically
+    disabled: HashSet<String>,
+    /// Keep output ids stable while players come and go
+    output_ids: HashMap<String, u32>,
+    next_output_id: u32,
+}
+
+impl BridgeCommand {
+    fn parse(message: &str) -> Option<BridgeCommand> {
+        let mut parts = message.split_whitespace();
+        let command = match (parts.next()?, parts.next()) {
+            ("list-players", None) => BridgeCommand::ListPlayers,
+            ("select-player", Some(bus_name)) => BridgeCommand::SelectPlayer(bus_name.to_string()),
+            ("release-player", None) => BridgeCommand::ReleasePlayer,
+            ("reload", None) => BridgeCommand::Reload,
+            _ => return None,
+        };
+        match parts.next() {
+            Some(_) => None,
+            None => Some(command),
+        }
+    }
+}
+
 struct MpdQueryState {
-    command_tx: mpsc::Sender<Command>,
+    command_tx: mpsc::Sender<PlayerCommand>,
+    client_id: u64,
+    /// Partition the connection is bound to, None for the default partition
+    partition: Option<String>,
+    /// Whether the listener pinned the partition, so the client cannot switch
+    partition_pinned: bool,
+    permissions: Permissions,
+    /// Upper bound for permissions granted by passwords
+    permission_limit: Permissions,
+    metadata_style: MetadataStyle,
     in_command_list: bool,
     in_command_list_ok: bool,
     command_list_ended: bool,
     command_list_count: usize,
     command_list_failed: bool,
-    last_idle_player_state: Option<PlayerStateForIdle>,
-    last_idle_playlist_state: Option<PlayerState>,
-    last_idle_mixer_state: Option<u8>,
+    idle: Arc<IdleClient>,
     should_close: bool,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 struct PlayerState {
     playback_status: mpris::PlaybackStatus,
     title: Option<String>,
     artist: Option<String>,
     duration: Option<f32>,
     elapsed: Option<f32>,
+    /// When elapsed was read, since the position is not updated between polls
+    elapsed_at: Option<Instant>,
     art_url: Option<String>,
+    album: Option<String>,
+    url: Option<String>,
+}
+
+impl PlayerState {
+    /// Position now, assuming playback continued at normal rate since it was read
+    fn current_elapsed(&self) -> Option<f32> {
+        let elapsed = self.elapsed?;
+        let Some(elapsed_at) = self.elapsed_at.filter(|_| self.playback_status == mpris::PlaybackStatus::Playing) else {
+            return Some(elapsed);
+        };
+        let elapsed = elapsed + elapsed_at.elapsed().as_secs_f32();
+        Some(self.duration.map_or(elapsed, |duration| elapsed.min(duration)))
+    }
+
+    fn uri(&self) -> Option<String> {
+        track_uri(&self.title, &self.artist, &self.album, &self.url)
+    }
+}
+
+/// Equal states read at different times are the same, so elapsed_at is left out
+impl PartialEq for PlayerState {
+    fn eq(&self, other: &PlayerState) -> bool {
+        self.playback_status == other.playback_status
+            && self.title == other.title
+            && self.artist == other.artist
+            && self.duration == other.duration
+            && self.elapsed == other.elapsed
+            && self.art_url == other.art_url
+            && self.album == other.album
+            && self.url == other.url
+    }
+}
+
+/// Stable identity of a track: its xesam:url if known, a hash of its metadata otherwise
+fn track_uri(
+    title: &Option<String>,
+    artist: &Option<String>,
+    album: &Option<String>,
+    url: &Option<String>,
+) -> Option<String> {
+    if let Some(url) = url {
+        return Some(url.clone());
+    }
+    if title.is_none() && artist.is_none() && album.is_none() {
+        return None;
+    }
+    // FNV-1a, since std hashers don't guarantee stable output across releases
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for field in [title, artist, album] {
+        for byte in field.as_deref().unwrap_or("").bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Some(format!("mpris:{hash:016x}"))
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -61,13 +336,40 @@ struct PlayerStateForIdle {
     title: Option<String>,
     artist: Option<String>,
     art_url: Option<String>,
-    single: bool,
 }
 
 struct MpdSharedState {
     player_state: Arc<RwLock<Option<PlayerState>>>,
     null_volume: AtomicU8,
     single_oneshot: AtomicBool,
+    history: Mutex<History>,
+    stickers: Mutex<StickerDb>,
+    channels: Mutex<ChannelRegistry>,
+    idle: IdleRegistry,
+    bridge_tx: mpsc::Sender<BridgeCommand>,
+    outputs: RwLock<Vec<OutputInfo>>,
+    /// State of players with connections bound to their partition, by partition name
+    partition_states: RwLock<HashMap<String, PlayerState>>,
+    /// Partition names by client id, for all connections not in the default partition
+    bound_partitions: Mutex<HashMap<u64, String>>,
+    /// Wakes up the observer to read the players of newly bound partitions
+    partitions_bound: tokio::sync::Notify,
+    /// Replaced when the config is reloaded
+    settings: RwLock<Arc<Settings>>,
+    /// Command line, which takes precedence over the config file on reloads
+    args: Args,
+    started_at: Instant,
+    /// Milliseconds any player spent playing, bridged or not
+    playtime_ms: AtomicU64,
+    systemd: systemd::Notifier,
+    /// Becomes true when the bridge is shutting down and clients should disconnect
+    shutdown: watch::Receiver<bool>,
+}
+
+impl MpdSharedState {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
 }
 
 #[derive(Debug)]
@@ -85,123 +387,563 @@ impl std::fmt::Display for MpdCommandError {
 }
 
 fn safe_command_print(command: &[u8]) -> &str {
-    match std::str::from_utf8(&command) {
+    match std::str::from_utf8(command) {
         Ok(s) => s,
         Err(_) => "[un-utf8 command]"
     }
 }
 
+// Error codes: https://github.com/MusicPlayerDaemon/MPD/blob/master/src/protocol/Ack.hxx
+const ACK_ERROR_ARG: i8 = 2;
+const ACK_ERROR_PASSWORD: i8 = 3;
+const ACK_ERROR_PERMISSION: i8 = 4;
+const ACK_ERROR_UNKNOWN: i8 = 5;
+const ACK_ERROR_NO_EXIST: i8 = 50;
+const ACK_ERROR_SYSTEM: i8 = 52;
+const ACK_ERROR_EXIST: i8 = 56;
+
 impl MpdCommandError {
     pub fn new(command: &[u8], message: &str) -> MpdCommandError {
-        let command_str = safe_command_print(&command);
+        MpdCommandError::with_code(command, message, ACK_ERROR_UNKNOWN)
+    }
+
+    pub fn with_code(command: &[u8], message: &str, mpd_error_code: i8) -> MpdCommandError {
+        let command_str = safe_command_print(command);
         MpdCommandError {
             //command: command.to_vec(),
             command_str: command_str.to_string(),
             message: message.to_string(),
-            // Error codes: https://github.com/MusicPlayerDaemon/MPD/blob/master/src/protocol/Ack.hxx
-            // 5 is unknown
-            mpd_error_code: 5,
+            mpd_error_code,
         }
     }
 }
 
+/// Error that command handlers can return to respond with a specific MPD error code
+#[derive(Debug)]
+struct MpdAck {
+    code: i8,
+    message: String,
+}
+impl std::error::Error for MpdAck {}
+impl std::fmt::Display for MpdAck {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn mpd_ack(code: i8, message: &str) -> anyhow::Error {
+    MpdAck {
+        code,
+        message: message.to_string(),
+    }.into()
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
 
     let args = Args::parse();
 
-    let address = format!("{}:{}", args.bind_address, args.port);
-    info!("Binding to address {address}...");
+    let settings = Settings::load(&args)?;
+
+    let history = match &settings.history_path {
+        Some(path) => History::load(path)?,
+        None => History::in_memory(),
+    };
+    if args.export_history {
+        println!("{}", history.export_json()?);
+        return Ok(());
+    }
 
-    let listener = TcpListener::bind(address).await?;
-    info!("Bound to address, listening...");
+    let activated_listeners = systemd::listen_fds()?;
+    let mut listeners = settings.listeners.clone();
+    // Sockets passed by systemd replace the default listener, just like --listen
+    if let Some(address) = &settings.tcp_address {
+        if listeners.is_empty() && activated_listeners.is_empty() {
+            listeners.push(ListenerConfig {
+                address: ListenAddress::Tcp(address.clone()),
+                profile: ListenerProfile::default(),
+            });
+        }
+    }
+    if let Some(path) = &settings.socket_path {
+        listeners.push(ListenerConfig {
+            address: ListenAddress::Unix(path.clone()),
+            profile: ListenerProfile {
+                trust_owner: settings.socket_trust_owner,
+                socket_mode: Some(settings.socket_mode),
+                ..ListenerProfile::default()
+            },
+        });
+    }
 
-    // TODO some signaling for idle in the other way round as well
     let (command_tx, command_rx) = mpsc::channel(8);
+    let (bridge_tx, bridge_rx) = mpsc::channel(8);
     let player_state = Arc::new(RwLock::new(None));
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (stop_observer_tx, stop_observer_rx) = watch::channel(false);
+    // Every client task holds a sender, so the receiver tells when all of them are done
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+    // Register early so signals during startup are not missed
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
 
+    let ducking = match &settings.volume_path {
+        Some(path) => Ducking::load(path)?,
+        None => Ducking::in_memory(),
+    };
     let shared_state = Arc::new(MpdSharedState {
         player_state: player_state.clone(),
         null_volume: AtomicU8::new(0),
         single_oneshot: AtomicBool::new(false),
+        history: Mutex::new(history),
+        stickers: Mutex::new(match &settings.sticker_path {
+            Some(path) => StickerDb::load(path)?,
+            None => StickerDb::in_memory(),
+        }),
+        channels: Mutex::new(ChannelRegistry::default()),
+        idle: IdleRegistry::new(),
+        bridge_tx,
+        outputs: RwLock::new(Vec::new()),
+        partition_states: RwLock::new(HashMap::new()),
+        bound_partitions: Mutex::new(HashMap::new()),
+        partitions_bound: tokio::sync::Notify::new(),
+        settings: RwLock::new(Arc::new(settings)),
+        args,
+        started_at: Instant::now(),
+        playtime_ms: AtomicU64::new(0),
+        systemd: systemd::Notifier::from_env(),
+        shutdown: shutdown_rx,
     });
 
     let shared_state_mpris = shared_state.clone();
     let command_tx_mpris = command_tx.clone();
 
+    if listeners.is_empty() && activated_listeners.is_empty() {
+        return Err(anyhow::anyhow!("Refusing to start without any listener, --no-tcp requires --listen or --socket"));
+    }
+
     // Accept incoming MPD clients
-    tokio::spawn(async move {
-        loop {
-            let (mut socket, addr) = listener.accept().await.unwrap();
-            info!("Connected client {addr}");
+    for listener in activated_listeners {
+        match listener {
+            systemd::ActivatedListener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)?;
+                info!("Listening on activated socket {:?}", listener.local_addr()?);
+                let profile = Arc::new(ListenerProfile::default());
+                tokio::spawn(accept_tcp(listener, profile, shared_state.clone(), command_tx.clone(), drain_tx.clone()));
+            }
+            systemd::ActivatedListener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = UnixListener::from_std(listener)?;
+                info!("Listening on activated socket {:?}", listener.local_addr()?);
+                let profile = Arc::new(ListenerProfile {
+                    trust_owner: shared_state.settings().socket_trust_owner,
+                    ..ListenerProfile::default()
+                });
+                tokio::spawn(accept_unix(listener, profile, shared_state.clone(), command_tx.clone(), drain_tx.clone()));
+            }
+        }
+    }
+    let mut socket_paths = Vec::new();
+    for ListenerConfig { address, profile } in listeners {
+        let profile = Arc::new(profile);
+        match address {
+            ListenAddress::Tcp(address) => {
+                info!("Binding to address {address}...");
+                let listener = TcpListener::bind(&address).await?;
+                info!("Bound to address {address}, listening...");
+                tokio::spawn(accept_tcp(listener, profile, shared_state.clone(), command_tx.clone(), drain_tx.clone()));
+            }
+            ListenAddress::Unix(path) => {
+                let listener = bind_unix_socket(&path, profile.socket_mode.unwrap_or(listener::DEFAULT_SOCKET_MODE))?;
+                socket_paths.push(path);
+                tokio::spawn(accept_unix(listener, profile, shared_state.clone(), command_tx.clone(), drain_tx.clone()));
+            }
+        }
+    }
 
-            let shared_state = shared_state.clone();
-            let command_tx = command_tx.clone();
-            tokio::spawn(async move {
-                let mut buf = [0; 1024];
-                if let Err(e) = socket.set_nodelay(true) {
-                    warn!("Failed to set nodelay: {:?}", e);
+    // Observe and control local MPRIS player on its own thread, clients only talk to it through channels
+    let mut observer_done = spawn_player_thread(move || {
+        observe_mpris(command_tx_mpris, command_rx, bridge_rx, stop_observer_rx, ducking, shared_state_mpris)
+    })?;
 
+    loop {
+        tokio::select! {
+            _ = &mut observer_done => return Err(anyhow::anyhow!("MPRIS observer stopped unexpectedly")),
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down..."),
+            _ = interrupt.recv() => info!("Received SIGINT, shutting down..."),
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading config...");
+                if let Err(e) = reload_settings(&shared_state) {
+                    error!("Keeping previous config: {e}");
                 }
+                continue;
+            }
+        }
+        break;
+    }
+    shared_state.systemd.stopping();
+    let deadline = shared_state.settings().shutdown_timeout;
+    let drain = async {
+        // Stop accepting and let clients finish what they are doing
+        let _ = shutdown_tx.send(true);
+        drop(drain_tx);
+        let _ = drain_rx.recv().await;
+        debug!("All clients disconnected");
+        // Commands of the last clients may still be queued for the player
+        let _ = stop_observer_tx.send(true);
+        let _ = (&mut observer_done).await;
+    };
+    let drained = timeout(deadline, drain).await.is_ok();
+    if !drained {
+        warn!("Shutdown did not finish within {deadline:?}, exiting anyway");
+        // Still let the observer restore volumes, unless it is stuck in a player call
+        let _ = stop_observer_tx.send(true);
+        if timeout(OBSERVER_STOP_GRACE, &mut observer_done).await.is_err() {
+            warn!("Player observer did not stop, ducked volumes are restored on the next start");
+        }
+    }
 
-                // Send initial greeting
-                if let Err(e) = socket.write_all(b"OK MPD 0.23.16\n").await {
-                    warn!("Failed to write to socket; err = {:?}", e);
-                    return;
-                }
+    match shared_state.history.lock() {
+        Ok(mut history) => history.finish_current(),
+        Err(_) => error!("Failed to lock history for shutdown"),
+    }
+    match shared_state.stickers.lock() {
+        Ok(stickers) => {
+            if let Err(e) = stickers.flush() {
+                error!("Failed to persist stickers: {e}");
+            }
+        }
+        Err(_) => error!("Failed to lock stickers for shutdown"),
+    }
+    for path in socket_paths {
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to remove socket {}: {e}", path.display());
+        }
+    }
+    info!("Shutdown complete");
+    if !drained {
+        // Do not wait for a player call that hangs
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-                let mut state = MpdQueryState {
-                    command_tx,
-                    in_command_list: false,
-                    in_command_list_ok: false,
-                    command_list_ended: false,
-                    command_list_count: 0,
-                    command_list_failed: false,
-                    last_idle_player_state: None,
-                    last_idle_playlist_state: None,
-                    last_idle_mixer_state: None,
-                    should_close: false,
-                };
+/// Run the player observer on a dedicated thread, since MPRIS calls block and a hanging player
+/// must not stall the runtime serving clients; the receiver completes when the observer stops
+fn spawn_player_thread<F, O>(observer: F) -> anyhow::Result<oneshot::Receiver<()>>
+where
+    // Players cannot be sent between threads, so the observer is only created on its thread
+    F: FnOnce() -> O + Send + 'static,
+    O: std::future::Future<Output = ()>,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build()?;
+    let (done_tx, done_rx) = oneshot::channel();
+    std::thread::Builder::new()
+        .name("mpris-player".to_string())
+        .spawn(move || {
+            runtime.block_on(observer());
+            let _ = done_tx.send(());
+        })?;
+    Ok(done_rx)
+}
 
-                loop {
-                    trace!("Reading from {addr}...");
-                    let n = match socket.read(&mut buf).await {
-                        // socket closed
-                        Ok(0) => {
-                            debug!("Socket closed: {addr}");
-                            return
-                        }
-                        Ok(n) => n,
-                        Err(e) => {
-                            warn!("Failed to read from socket; err = {:?}", e);
-                            return;
-                        }
-                    };
-                    trace!("Done reading {n} from {addr}");
+/// Read the config file again, keeping the current settings if it is invalid
+fn reload_settings(shared_state: &MpdSharedState) -> anyhow::Result<()> {
+    let settings = Settings::load(&shared_state.args)?;
+    let Ok(mut current) = shared_state.settings.write() else {
+        return Err(anyhow::anyhow!("Failed to lock settings for reload"));
+    };
+    for key in current.restart_required(&settings) {
+        warn!("Changing {key} requires a restart");
+    }
+    *current = Arc::new(settings);
+    info!("Reloaded config");
+    Ok(())
+}
 
-                    // Handle commands
-                    if let Err(e) = handle_mpd_queries(&mut socket, &buf[0..n], &mut state, shared_state.clone()).await {
-                        warn!("Failed to handle MPD queries: {:?}", e);
-                        return;
-                    }
-                    if state.should_close {
-                        // Socket will close automatically when out of scope
-                        return;
-                    }
+async fn accept_tcp(
+    listener: TcpListener,
+    profile: Arc<ListenerProfile>,
+    shared_state: Arc<MpdSharedState>,
+    command_tx: mpsc::Sender<PlayerCommand>,
+    drain_tx: mpsc::Sender<()>,
+) {
+    let mut shutdown = shared_state.shutdown.clone();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.changed() => return,
+        };
+        let (socket, addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept on TCP socket: {:?}", e);
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let settings = shared_state.settings();
+        let default_permissions = profile.default_permissions.unwrap_or(settings.default_permissions);
+        let access_control = settings.access_control.with_listener(&profile.access);
+        let Some(permissions) = access_control.check(addr.ip(), default_permissions) else {
+            warn!("Rejected client {addr} by access control");
+            continue;
+        };
+        if let Err(e) = socket.set_nodelay(true) {
+            warn!("Failed to set nodelay: {:?}", e);
+        }
+        let permissions = permissions & profile.permission_limit();
+        info!("Connected client {addr} with permissions {permissions}");
+        spawn_client(socket, addr.to_string(), permissions, profile.clone(), shared_state.clone(), command_tx.clone(), drain_tx.clone());
+    }
+}
+
+async fn accept_unix(
+    listener: UnixListener,
+    profile: Arc<ListenerProfile>,
+    shared_state: Arc<MpdSharedState>,
+    command_tx: mpsc::Sender<PlayerCommand>,
+    drain_tx: mpsc::Sender<()>,
+) {
+    let mut shutdown = shared_state.shutdown.clone();
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let own_uid = unsafe { libc::geteuid() };
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.changed() => return,
+        };
+        let socket = match accepted {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                warn!("Failed to accept on Unix socket: {:?}", e);
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let (peer, uid) = match socket.peer_cred() {
+            Ok(cred) => (format!("unix:uid={}", cred.uid()), Some(cred.uid())),
+            Err(e) => {
+                warn!("Failed to get peer credentials: {:?}", e);
+                (String::from("unix"), None)
+            }
+        };
+        let permissions = match profile.trust_owner && uid == Some(own_uid) {
+            true => Permissions::ALL,
+            false => profile.default_permissions.unwrap_or(shared_state.settings().default_permissions),
+        };
+        let permissions = permissions & profile.permission_limit();
+        info!("Connected client {peer} with permissions {permissions}");
+        spawn_client(socket, peer, permissions, profile.clone(), shared_state.clone(), command_tx.clone(), drain_tx.clone());
+    }
+}
+
+/// Bind a Unix socket, replacing a stale socket file of a previous run
+fn bind_unix_socket(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    info!("Binding to socket {}...", path.display());
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(anyhow::anyhow!("Socket {} is already in use", path.display()));
+            }
+            debug!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(anyhow::anyhow!("{} exists and is not a socket", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)?;
+    // Bind in a private directory first, so the socket is never reachable with the umask's mode
+    let private_dir = parent.join(format!(".bind-{}", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join("socket");
+    let bound = UnixListener::bind(&private_path).map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))?;
+            std::fs::rename(&private_path, path)?;
+            Ok(listener)
+        });
+    if let Err(e) = std::fs::remove_dir_all(&private_dir) {
+        warn!("Failed to remove {}: {:?}", private_dir.display(), e);
+    }
+    let listener = bound?;
+    info!("Bound to socket, listening...");
+    Ok(listener)
+}
+
+fn spawn_client<S: MpdSocket + 'static>(
+    socket: S,
+    addr: String,
+    permissions: Permissions,
+    profile: Arc<ListenerProfile>,
+    shared_state: Arc<MpdSharedState>,
+    command_tx: mpsc::Sender<PlayerCommand>,
+    drain_tx: mpsc::Sender<()>,
+) {
+    tokio::spawn(async move {
+        let _drain_tx = drain_tx;
+        let client_id = match shared_state.channels.lock() {
+            Ok(mut channels) => channels.register_client(),
+            Err(_) => {
+                error!("Failed to lock channels for {addr}");
+                return;
+            }
+        };
+        serve_client(socket, &addr, client_id, permissions, &profile, shared_state.clone(), command_tx).await;
+        match shared_state.channels.lock() {
+            Ok(mut channels) => {
+                if channels.unregister_client(client_id) {
+                    shared_state.idle.notify(Subsystems::SUBSCRIPTION, IdleScope::All);
                 }
-            });
+            }
+            Err(_) => error!("Failed to lock channels to unregister {addr}"),
+        }
+        match shared_state.bound_partitions.lock() {
+            Ok(mut bound_partitions) => {
+                bound_partitions.remove(&client_id);
+            }
+            Err(_) => error!("Failed to lock partitions to unregister {addr}"),
         }
+        shared_state.idle.unregister(client_id);
     });
+}
 
-    // Observe and control local MPRIS player
-    observe_mpris(command_tx_mpris, command_rx, shared_state_mpris).await;
+async fn serve_client<S: MpdSocket>(
+    mut socket: S,
+    addr: &str,
+    client_id: u64,
+    permissions: Permissions,
+    profile: &ListenerProfile,
+    shared_state: Arc<MpdSharedState>,
+    command_tx: mpsc::Sender<PlayerCommand>,
+) {
+    let mut buf = [0; 1024];
 
-    Ok(())
+    // Send initial greeting
+    let settings = shared_state.settings();
+    let version = profile.protocol_version.as_deref().unwrap_or(&settings.protocol_version);
+    let greeting = format!("OK MPD {version}\n");
+    if let Err(e) = socket.write_all(greeting.as_bytes()).await {
+        warn!("Failed to write to socket; err = {:?}", e);
+        return;
+    }
+
+    let mut state = MpdQueryState {
+        command_tx,
+        client_id,
+        partition: None,
+        partition_pinned: false,
+        permissions,
+        permission_limit: profile.permission_limit(),
+        metadata_style: profile.metadata_style,
+        in_command_list: false,
+        in_command_list_ok: false,
+        command_list_ended: false,
+        command_list_count: 0,
+        command_list_failed: false,
+        idle: shared_state.idle.register(client_id),
+        should_close: false,
+    };
+    if let Some(pattern) = &profile.player {
+        state.partition_pinned = true;
+        pin_partition(pattern, &mut state, &shared_state, addr);
+    }
+
+    let mut shutdown = shared_state.shutdown.clone();
+    loop {
+        trace!("Reading from {addr}...");
+        let read = tokio::select! {
+            read = socket.read(&mut buf) => read,
+            _ = shutdown.changed() => {
+                debug!("Closing {addr} for shutdown");
+                return;
+            }
+        };
+        let n = match read {
+            // socket closed
+            Ok(0) => {
+                debug!("Socket closed: {addr}");
+                return
+            }
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Failed to read from socket; err = {:?}", e);
+                return;
+            }
+        };
+        trace!("Done reading {n} from {addr}");
+
+        // Follow the pinned player if it was not running before, or was replaced
+        if let Some(pattern) = &profile.player {
+            pin_partition(pattern, &mut state, &shared_state, addr);
+        }
+
+        // Handle commands
+        if let Err(e) = handle_mpd_queries(&mut socket, &buf[0..n], &mut state, shared_state.clone()).await {
+            warn!("Failed to handle MPD queries: {:?}", e);
+            return;
+        }
+        if state.should_close {
+            // Socket will close automatically when out of scope
+            return;
+        }
+    }
+}
+
+/// Bind a connection to the partition of the first player matching the listener's pattern,
+/// keeping the previous one if no player matches
+fn pin_partition(pattern: &PlayerPattern, state: &mut MpdQueryState, shared_state: &MpdSharedState, addr: &str) {
+    let partition = match shared_state.outputs.read() {
+        Ok(outputs) => outputs.iter()
+            .find(|output| pattern.matches_names(&output.bus_name, &output.identity))
+            .map(|output| partition_name(&output.bus_name).to_string()),
+        Err(_) => {
+            error!("Failed to read outputs to pin {addr}");
+            None
+        }
+    };
+    // Until a player matches, the partition is one no player is named after
+    let partition = partition.or_else(|| state.partition.clone()).unwrap_or_else(|| pattern.to_string());
+    match shared_state.bound_partitions.lock() {
+        Ok(mut bound_partitions) => {
+            if bound_partitions.get(&state.client_id) == Some(&partition) {
+                return;
+            }
+            bound_partitions.insert(state.client_id, partition.clone());
+            shared_state.partitions_bound.notify_one();
+        }
+        Err(_) => {
+            error!("Failed to lock partitions to pin {addr}");
+            return;
+        }
+    }
+    debug!("Pinned {addr} to partition {partition}");
+    // Report the new player's state on the next idle, like switching partitions
+    if state.partition.as_ref().is_some_and(|previous| *previous != partition) {
+        state.idle.mark(Subsystems::PLAYER | Subsystems::PLAYLIST);
+    }
+    state.idle.set_partition(Some(partition.clone()));
+    state.partition = Some(partition);
+}
+
+fn update_history(
+    shared_state: &MpdSharedState,
+    player: Option<&Player>,
+    state: Option<&PlayerState>,
+) {
+    match shared_state.history.lock() {
+        Ok(mut history) => history.update(player.map(|p| (p.bus_name(), p.identity())), state),
+        Err(_) => error!("Failed to lock history"),
+    }
 }
 
 fn try_set_player_state(
-    player_state: &Arc<RwLock<Option<PlayerState>>>,
+    shared_state: &MpdSharedState,
     value: Option<PlayerState>,
     last_emitted_value: &mut Option<PlayerState>,
 ) {
@@ -209,31 +951,440 @@ fn try_set_player_state(
     if *last_emitted_value == value {
         return
     }
-    match player_state.write() {
-        Ok(mut guard) => {
-            *guard = value.clone();
-            *last_emitted_value = value;
-            trace!("Player state updated");
+    match shared_state.player_state.write() {
+        Ok(mut guard) => {
+            *guard = value.clone();
+            trace!("Player state updated");
+        }
+        Err(_) => {
+            error!("Failed to write player state");
+            return;
+        }
+    }
+    let changed = get_idle_changes(last_emitted_value.as_ref(), value.as_ref());
+    *last_emitted_value = value;
+    if !changed.is_empty() {
+        shared_state.idle.notify(changed, IdleScope::Partition(None));
+    }
+}
+
+fn execute_command(player: &Player, command: Command) -> anyhow::Result<()> {
+    let (action, result) = match command {
+        Command::Play => ("play", player.checked_play()),
+        Command::Pause => ("pause", player.checked_pause()),
+        // Some players don't properly support stop, in which case pause is good enough
+        Command::Stop => match player.checked_stop() {
+            Ok(true) => ("stop", Ok(true)),
+            result => {
+                warn!("Stopping failed, pausing instead: {result:?}");
+                ("stop or pause", player.checked_pause())
+            }
+        },
+        Command::Next => ("go next", player.checked_next()),
+        Command::Prev => ("go previous", player.checked_previous()),
+    };
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(mpd_ack(ACK_ERROR_SYSTEM, &format!("Player {} cannot {action}", player.identity()))),
+        Err(e) => Err(mpd_ack(ACK_ERROR_SYSTEM, &format!("Player {} failed to {action}: {e}", player.identity()))),
+    }
+}
+
+fn read_player_state(player: &Player) -> anyhow::Result<PlayerState> {
+    let playback_status = player.get_playback_status()
+        .map_err(|e| anyhow::anyhow!("Failed to read playback status, {e}"))?;
+    let metadata = player.get_metadata()
+        .map_err(|e| anyhow::anyhow!("Failed to read metadata, {e}"))?;
+    let mut state = PlayerState {
+        playback_status,
+        title: None,
+        artist: None,
+        duration: None,
+        elapsed: player.get_position().map(|d| d.as_secs_f32()).ok(),
+        elapsed_at: Some(Instant::now()),
+        art_url: None,
+        album: None,
+        url: None,
+    };
+    set_metadata(&mut state, &metadata);
+    Ok(state)
+}
+
+fn set_metadata(state: &mut PlayerState, metadata: &mpris::Metadata) {
+    state.title = metadata.title().map(|t| t.into());
+    state.artist = metadata.artists().map(|a| a.join(", "));
+    state.duration = metadata.length().map(|d| d.as_secs_f32());
+    state.art_url = metadata.art_url().map(|u| u.into());
+    state.album = metadata.album_name().map(|a| a.into());
+    state.url = metadata.url().map(|u| u.into());
+}
+
+/// Apply the changes sent in signals, only asking the player for its position if it is off now
+fn apply_player_changes(player: &Player, state: &mut PlayerState, changes: BridgedChanges) {
+    let moved = changes.playback_status.is_some() || changes.metadata.is_some();
+    if let Some(playback_status) = changes.playback_status {
+        state.playback_status = playback_status;
+    }
+    if let Some(metadata) = &changes.metadata {
+        set_metadata(state, metadata);
+    }
+    match changes.position {
+        Some(position) => state.elapsed = Some(position.as_secs_f32()),
+        // Started, stopped or changed tracks, so the position cannot be extrapolated from the last one
+        None if moved => state.elapsed = player.get_position().map(|d| d.as_secs_f32()).ok(),
+        None => return,
+    }
+    state.elapsed_at = Some(Instant::now());
+}
+
+/// Update the state of all players that have connections bound to their partition
+fn poll_partitions(
+    shared_state: &MpdSharedState,
+    bridged: Option<(&Player, &PlayerState)>,
+    partition_players: &mut HashMap<String, Player>,
+) {
+    let partitions: HashSet<String> = match shared_state.bound_partitions.lock() {
+        Ok(bound_partitions) => bound_partitions.values().cloned().collect(),
+        Err(_) => {
+            error!("Failed to lock bound partitions");
+            return;
+        }
+    };
+    partition_players.retain(|partition, _| partitions.contains(partition));
+    let mut states = HashMap::new();
+    for partition in partitions {
+        // No need to ask the bridged player twice
+        if let Some((player, state)) = bridged {
+            if partition_name(player.bus_name()) == partition {
+                states.insert(partition, state.clone());
+                continue;
+            }
+        }
+        if !partition_players.contains_key(&partition) {
+            match find_player_by_bus_name(&partition) {
+                Ok(player) => {
+                    partition_players.insert(partition.clone(), player);
+                }
+                Err(e) => {
+                    trace!("No player for partition {partition}: {e}");
+                    continue;
+                }
+            }
+        }
+        let Some(player) = partition_players.get(&partition) else {
+            continue;
+        };
+        match read_player_state(player) {
+            Ok(state) => {
+                states.insert(partition, state);
+            }
+            Err(e) => {
+                debug!("Failed to poll partition {partition}: {e}");
+                partition_players.remove(&partition);
+            }
+        }
+    }
+    let mut changes = Vec::new();
+    match shared_state.partition_states.write() {
+        Ok(mut guard) => {
+            if *guard != states {
+                let partitions: HashSet<&String> = guard.keys().chain(states.keys()).collect();
+                for partition in partitions {
+                    let changed = get_idle_changes(guard.get(partition), states.get(partition));
+                    if !changed.is_empty() {
+                        changes.push((partition.clone(), changed));
+                    }
+                }
+                *guard = states;
+                trace!("Partition states updated");
+            }
+        }
+        Err(_) => error!("Failed to write partition states"),
+    }
+    for (partition, changed) in changes {
+        shared_state.idle.notify(changed, IdleScope::Partition(Some(partition)));
+    }
+}
+
+fn bridge_reply(shared_state: &MpdSharedState, message: &str) {
+    // Messages are sent as a single protocol line, but errors like those of the config parser span several
+    let message = message.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(" ");
+    let message = message.as_str();
+    debug!("Bridge reply: {message}");
+    match shared_state.channels.lock() {
+        Ok(mut channels) => {
+            // Nobody listening for replies is fine
+            if let Ok(receivers) = channels.send_message(BRIDGE_CHANNEL, message) {
+                shared_state.idle.notify(Subsystems::MESSAGE, IdleScope::Clients(receivers));
+            }
+        }
+        Err(_) => error!("Failed to lock channels for bridge reply"),
+    }
+}
+
+/// Publish the currently visible players as MPD outputs
+fn refresh_outputs(
+    shared_state: &MpdSharedState,
+    player: Option<&Player>,
+    players: &[FoundPlayer],
+    selection: &mut PlayerSelection,
+) {
+    selection.output_ids.retain(|bus_name, _| players.iter().any(|found| found.player.bus_name() == bus_name));
+    let outputs: Vec<OutputInfo> = players.iter().map(|found| {
+        let p = &found.player;
+        let id = *selection.output_ids.entry(p.bus_name().to_string()).or_insert_with(|| {
+            selection.next_output_id += 1;
+            selection.next_output_id - 1
+        });
+        OutputInfo {
+            id,
+            bus_name: p.bus_name().to_string(),
+            identity: p.identity().to_string(),
+            enabled: player.map(|player| player.unique_name() == p.unique_name()).unwrap_or(false),
+        }
+    }).collect();
+    match shared_state.outputs.write() {
+        Ok(mut guard) => {
+            if *guard != outputs {
+                debug!("Outputs changed: {outputs:?}");
+                // Partitions are named after the players, see get_partitions
+                let partitions_changed = guard.iter().map(|output| partition_name(&output.bus_name))
+                    .ne(outputs.iter().map(|output| partition_name(&output.bus_name)));
+                *guard = outputs;
+                let changed = match partitions_changed {
+                    true => Subsystems::OUTPUT | Subsystems::PARTITION,
+                    false => Subsystems::OUTPUT,
+                };
+                shared_state.idle.notify(changed, IdleScope::All);
+            }
+        }
+        Err(_) => error!("Failed to write outputs"),
+    }
+}
+
+/// Execute a command sent to the bridge channel, returns true if the player needs to be selected again
+fn handle_bridge_command(
+    command: BridgeCommand,
+    player: Option<&Player>,
+    selection: &mut PlayerSelection,
+    shared_state: &MpdSharedState,
+) -> bool {
+    info!("Handle bridge command {command:?}");
+    match command {
+        BridgeCommand::ListPlayers => {
+            let players = PlayerFinder::new()
+                .map_err(anyhow::Error::from)
+                .and_then(|finder| Ok(finder.find_all()?));
+            match players {
+                Ok(players) => {
+                    for p in players {
+                        let bridged = match player {
+                            Some(player) if player.unique_name() == p.unique_name() => "bridged",
+                            _ => "available",
+                        };
+                        bridge_reply(shared_state, &format!("player {} {bridged} {}", p.bus_name(), p.identity()));
+                    }
+                    bridge_reply(shared_state, "ok list-players");
+                }
+                Err(e) => bridge_reply(shared_state, &format!("error list-players: {e}")),
+            }
+            false
+        }
+        BridgeCommand::SelectPlayer(bus_name) => {
+            match find_player_by_bus_name(&bus_name) {
+                Ok(selected) => {
+                    selection.disabled.remove(selected.bus_name());
+                    selection.pinned = Some(bus_name.clone());
+                    bridge_reply(shared_state, &format!("ok select-player {bus_name}"));
+                    true
+                }
+                Err(e) => {
+                    bridge_reply(shared_state, &format!("error select-player: {e}"));
+                    false
+                }
+            }
+        }
+        BridgeCommand::ReleasePlayer => {
+            selection.pinned = None;
+            bridge_reply(shared_state, "ok release-player");
+            true
+        }
+        BridgeCommand::DisablePlayer(bus_name) => {
+            let is_pinned = selection.pinned.as_deref() == Some(&bus_name);
+            let is_bridged = player.map(|player| player.bus_name() == bus_name).unwrap_or(false);
+            if is_pinned {
+                selection.pinned = None;
+            }
+            info!("Disabling player {bus_name}");
+            selection.disabled.insert(bus_name);
+            is_pinned || is_bridged
+        }
+        BridgeCommand::Reload => match reload_settings(shared_state) {
+            Ok(()) => {
+                bridge_reply(shared_state, "ok reload");
+                true
+            }
+            Err(e) => {
+                error!("Keeping previous config: {e}");
+                bridge_reply(shared_state, &format!("error reload: {e}"));
+                false
+            }
+        },
+    }
+}
+
+/// Fail a command without executing it, telling the client why
+fn reject_command(command: PlayerCommand, message: &str) {
+    warn!("Rejecting command {:?}: {message}", command.command);
+    if let Some(reply) = command.reply {
+        let _ = reply.send(Err(mpd_ack(ACK_ERROR_SYSTEM, message)));
+    }
+}
+
+/// Keep a command until a player appears, or reject it right away if queueing is disabled
+fn queue_command(command: PlayerCommand, pending_commands: &mut VecDeque<(Instant, PlayerCommand)>, ttl: Duration) {
+    if ttl.is_zero() {
+        reject_command(command, "No player");
+        return;
+    }
+    debug!("Queueing command {:?} until a player appears", command.command);
+    pending_commands.push_back((Instant::now(), command));
+}
+
+fn expire_commands(pending_commands: &mut VecDeque<(Instant, PlayerCommand)>, ttl: Duration) {
+    while pending_commands.front().is_some_and(|(queued_at, _)| queued_at.elapsed() >= ttl) {
+        if let Some((_, command)) = pending_commands.pop_front() {
+            reject_command(command, "No player appeared in time");
+        }
+    }
+}
+
+/// Execute a command on the bridged player, or the player of the command's partition
+fn dispatch_command(command: PlayerCommand, player: Option<&Player>, partition_players: &HashMap<String, Player>) {
+    let bridged = player.filter(|player| {
+        command.partition.as_deref().is_none_or(|partition| partition_name(player.bus_name()) == partition)
+    });
+    let result = match (bridged, &command.partition) {
+        (Some(player), _) => execute_command(player, command.command),
+        (None, Some(partition)) => match partition_players.get(partition) {
+            Some(partition_player) => execute_command(partition_player, command.command),
+            None => match find_player_by_bus_name(partition) {
+                Ok(partition_player) => execute_command(&partition_player, command.command),
+                Err(e) => Err(mpd_ack(ACK_ERROR_NO_EXIST, &e.to_string())),
+            },
+        },
+        (None, None) => Err(mpd_ack(ACK_ERROR_SYSTEM, "No player")),
+    };
+    if let Err(e) = &result {
+        error!("Failed to execute command {:?}: {e}", command.command);
+    }
+    if let Some(reply) = command.reply {
+        // The client may have disconnected in the meantime
+        let _ = reply.send(result);
+    }
+}
+
+/// Pin the player from the config when it changed, returns true if the selection changed
+fn apply_configured_player(settings: &Settings, configured_player: &mut Option<String>, selection: &mut PlayerSelection) -> bool {
+    if settings.player == *configured_player {
+        return false;
+    }
+    info!("Configured player changed to {:?}", settings.player);
+    configured_player.clone_from(&settings.player);
+    selection.pinned.clone_from(&settings.player);
+    true
+}
+
+/// Measures how long any player was playing, across switches of the bridged player
+struct PlaytimeClock {
+    playing: bool,
+    since: Instant,
+}
+
+impl PlaytimeClock {
+    fn new() -> PlaytimeClock {
+        PlaytimeClock {
+            playing: false,
+            since: Instant::now(),
+        }
+    }
+
+    /// Count the time since the last update if something was playing, then start over
+    fn update(&mut self, shared_state: &MpdSharedState, playing: bool) {
+        let now = Instant::now();
+        if self.playing {
+            let played = now.duration_since(self.since).as_millis() as u64;
+            shared_state.playtime_ms.fetch_add(played, Ordering::SeqCst);
         }
-        Err(_) => error!("Failed to write player state"),
+        self.playing = playing;
+        self.since = now;
     }
 }
 
+fn notify_bridged_player(shared_state: &MpdSharedState, player: &Player) {
+    shared_state.systemd.status(&format!("Bridging {} ({})", player.identity(), player.bus_name()));
+}
+
 async fn observe_mpris(
-    command_tx: mpsc::Sender<Command>,
-    mut command_rx: mpsc::Receiver<Command>,
+    command_tx: mpsc::Sender<PlayerCommand>,
+    mut command_rx: mpsc::Receiver<PlayerCommand>,
+    mut bridge_rx: mpsc::Receiver<BridgeCommand>,
+    mut stop: watch::Receiver<bool>,
+    ducking: Ducking,
     shared_state: Arc<MpdSharedState>,
 ) {
-    let fail_delay = Duration::from_millis(1500);
-    let slow_poll_delay = Duration::from_millis(1000);
-    let fast_poll_delay = Duration::from_millis(100);
-    let mut poll_delay = slow_poll_delay;
+    let mut settings = shared_state.settings();
+    let mut poll_delay;
     let mut last_connect_err = None;
     let mut last_emitted_player_state = None;
-    loop {
-        try_set_player_state(&shared_state.player_state, None, &mut last_emitted_player_state);
-        let mut player = match find_mpris_player() {
-            Ok(player) => player,
+    let mut playtime = PlaytimeClock::new();
+    // Whether players other than the bridged one were playing when last listed
+    let mut others_playing;
+    let mut selection = PlayerSelection {
+        pinned: settings.player.clone(),
+        ..PlayerSelection::default()
+    };
+    let mut configured_player = settings.player.clone();
+    let mut last_outputs_refresh;
+    let mut names_changed = false;
+    let mut partition_players = HashMap::new();
+    let mut switch = SwitchState::default();
+    let mut exclusive = ExclusivePlayback::default();
+    let mut ducking = ducking;
+    let mut pending_commands = VecDeque::new();
+    let (events_tx, mut events_rx) = mpsc::channel(1);
+    let pending_changes = Arc::new(PendingChanges::default());
+    let mut signals = match signals::spawn_watcher(events_tx, pending_changes.clone()) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Cannot watch MPRIS signals, polling instead. {e}");
+            false
+        }
+    };
+    // Listeners are bound before we get here, so we are ready to serve clients
+    shared_state.systemd.ready();
+    'observe: loop {
+        shared_state.systemd.watchdog();
+        try_set_player_state(&shared_state, None, &mut last_emitted_player_state);
+        update_history(&shared_state, None, None);
+        // Listing players covers any that came or went, or changed, in the meantime
+        pending_changes.take_names();
+        pending_changes.take();
+        let players = selection::find_players();
+        let found = match &players {
+            Ok(players) => match restore_previous_player(players, &mut switch, &selection, &settings.selection) {
+                Some(i) => Ok(i),
+                None => find_mpris_player(players, &selection, &settings.selection),
+            },
+            Err(e) => Err(anyhow::anyhow!("{e}")),
+        };
+        let mut players = players.unwrap_or_default();
+        others_playing = players.iter().any(|found| found.status == Some(mpris::PlaybackStatus::Playing));
+        let mut player = match found {
+            Ok(i) => {
+                refresh_outputs(&shared_state, Some(&players[i].player), &players, &mut selection);
+                players.swap_remove(i).player
+            }
             Err(e) => {
                 let connect_err = Some(format!("{e}"));
                 if last_connect_err != connect_err {
@@ -242,102 +1393,246 @@ async fn observe_mpris(
                     trace!("Still cannot select MPRIS player. {}", e);
                 }
                 last_connect_err = connect_err;
-                sleep(fail_delay).await;
+                playtime.update(&shared_state, others_playing);
+                expire_commands(&mut pending_commands, settings.command_ttl);
+                refresh_outputs(&shared_state, None, &players, &mut selection);
+                poll_partitions(&shared_state, None, &mut partition_players);
+                shared_state.systemd.status("No MPRIS player");
+                // Any player starting to play may be the one to bridge
+                pending_changes.watch(None, HashSet::new(), true);
+                // Background players may still be ducked without any player to bridge
+                let mut wait = settings.fail_delay;
+                if (!settings.duck_foreground.is_empty() || ducking.is_active())
+                    && ducking.check(&players, &settings.duck_foreground, settings.duck_level, settings.duck_ramp) {
+                    wait = settings.fast_poll_delay;
+                }
+                // Bridge commands may change the player selection, so keep serving them while waiting
+                tokio::select! {
+                    result = timeout(shared_state.systemd.watchdog_delay(wait), bridge_rx.recv()) => {
+                        if let Ok(Some(command)) = result {
+                            handle_bridge_command(command, None, &mut selection, &shared_state);
+                        }
+                    }
+                    Some(command) = command_rx.recv() => match command.partition {
+                        // Players of other partitions do not need a bridged player
+                        Some(_) => dispatch_command(command, None, &partition_players),
+                        None => queue_command(command, &mut pending_commands, settings.command_ttl),
+                    },
+                    event = events_rx.recv(), if signals => match event {
+                        Some(event) => debug!("Retrying to select a player after {event:?}"),
+                        None => {
+                            warn!("Lost MPRIS signals, polling instead");
+                            signals = false;
+                        }
+                    },
+                    _ = stop.changed() => {
+                        while let Ok(command) = command_rx.try_recv() {
+                            match command.partition {
+                                Some(_) => dispatch_command(command, None, &partition_players),
+                                None => reject_command(command, "Shutting down without player"),
+                            }
+                        }
+                        for (_, command) in pending_commands.drain(..) {
+                            reject_command(command, "Shutting down without player");
+                        }
+                        break 'observe;
+                    }
+                }
+                settings = shared_state.settings();
+                apply_configured_player(&settings, &mut configured_player, &mut selection);
                 continue;
             }
         };
         info!("Connected to MPRIS player. {:?}", player);
+        notify_bridged_player(&shared_state, &player);
         last_connect_err = None;
+        last_outputs_refresh = Instant::now();
+        expire_commands(&mut pending_commands, settings.command_ttl);
+        for (_, command) in pending_commands.drain(..) {
+            info!("Replaying queued command {:?}", command.command);
+            dispatch_command(command, Some(&player), &partition_players);
+        }
+        // Read the state of the new player right away
+        let mut next_poll = Instant::now();
+        // Whether the bridged player needs to be read completely, rather than updated from signals
+        let mut reread = true;
         loop {
-            match timeout(poll_delay, command_rx.recv()).await {
-                Ok(Some(command)) => {
-                    debug!("Handle command {command:?}");
-                    match command {
-                        Command::Play => {
-                            if let Err(e) = player.play() {
-                                error!("Failed to execute command {command:?}: {e}");
-                            }
-                        },
-                        Command::Pause => {
-                            if let Err(e) = player.pause() {
-                                error!("Failed to execute command {command:?}: {e}");
-                            }
-                        },
-                        Command::Stop => {
-                            if let Err(e) = player.stop() {
-                                error!("Failed to execute command {command:?}: {e}");
-                            }
-                        },
-                        Command::Next => {
-                            if let Err(e) = player.next() {
-                                error!("Failed to execute command {command:?}: {e}");
-                            }
-                        },
-                        Command::Prev => {
-                            if let Err(e) = player.previous() {
-                                error!("Failed to execute command {command:?}: {e}");
-                            }
-                        },
+            let mut partitions_bound = false;
+            tokio::select! {
+                command = command_rx.recv() => match command {
+                    Some(command) => {
+                        debug!("Handle command {command:?}");
+                        dispatch_command(command, Some(&player), &partition_players);
+                        reread = true;
+                    }
+                    None => warn!("Command channel closed"),
+                },
+                _ = stop.changed() => {
+                    while let Ok(command) = command_rx.try_recv() {
+                        debug!("Handle pending command {command:?} before shutdown");
+                        dispatch_command(command, Some(&player), &partition_players);
                     }
+                    break 'observe;
                 }
-                Ok(None) => warn!("Command channel closed"),
-                Err(_) => trace!("Polling"),
-            }
-            let playback_status = match player.get_playback_status() {
-                Ok(status) => status,
-                Err(e) => {
-                    warn!("Failed to read playback status, {}", e);
-                    break;
+                Some(command) = bridge_rx.recv() => {
+                    if handle_bridge_command(command, Some(&player), &mut selection, &shared_state) {
+                        break;
+                    }
+                    reread = true;
                 }
-            };
-            let metadata = match player.get_metadata() {
-                Ok(metadata) => metadata,
-                Err(e) => {
-                    warn!("Failed to read metadata, {}", e);
-                    break;
+                event = events_rx.recv(), if signals => match event {
+                    Some(event) => trace!("Woken up by {event:?}"),
+                    None => {
+                        warn!("Lost MPRIS signals, polling instead");
+                        signals = false;
+                    }
+                },
+                _ = shared_state.partitions_bound.notified() => partitions_bound = true,
+                _ = sleep(shared_state.systemd.watchdog_delay(next_poll.saturating_duration_since(Instant::now()))) => {}
+            }
+            shared_state.systemd.watchdog();
+            // Without signals every wakeup polls, with them only the fallback poll does
+            let polling = !signals || Instant::now() >= next_poll;
+            if polling {
+                trace!("Polling");
+            }
+            let changes = pending_changes.take();
+            // Update outputs right away when players came or went, the bridged player is checked below
+            names_changed |= pending_changes.take_names();
+            settings = shared_state.settings();
+            if apply_configured_player(&settings, &mut configured_player, &mut selection) {
+                break;
+            }
+            // The bridged player may be gone when players came or went, reading it tells
+            let full_read = polling || reread || changes.bridged.reread || names_changed;
+            reread = false;
+            let bridged_changed = full_read || !changes.bridged.is_empty();
+            let bridged_status_changed = full_read || changes.bridged.playback_status.is_some();
+            let state = match (full_read, last_emitted_player_state.clone()) {
+                (false, Some(mut state)) => {
+                    apply_player_changes(&player, &mut state, changes.bridged);
+                    state
                 }
+                _ => match read_player_state(&player) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        warn!("{e}");
+                        break;
+                    }
+                },
             };
-            let state = PlayerState {
-                playback_status,
-                title: metadata.title().map(|t| t.into()),
-                artist: metadata.artists().map(|a| a.join(", ")),
-                duration: metadata.length().map(|d| d.as_secs_f32()),
-                elapsed: player.get_position().map(|d| d.as_secs_f32()).ok(),
-                art_url: metadata.art_url().map(|u| u.into()),
-            };
+            playtime.update(&shared_state, others_playing || state.playback_status == mpris::PlaybackStatus::Playing);
+            update_history(&shared_state, Some(&player), Some(&state));
+            if polling || bridged_changed || changes.partitions || partitions_bound {
+                poll_partitions(&shared_state, Some((&player, &state)), &mut partition_players);
+            }
             let state = Some(state);
             if shared_state.single_oneshot.load(Ordering::SeqCst) {
-                if state.as_ref().map(|s| get_state_for_single_oneshot(s)) != last_emitted_player_state.as_ref().map(|s| get_state_for_single_oneshot(s)) {
-                    match command_tx.send(Command::Pause).await {
+                if state.as_ref().map(get_state_for_single_oneshot) != last_emitted_player_state.as_ref().map(get_state_for_single_oneshot) {
+                    let command = PlayerCommand {
+                        partition: None,
+                        command: Command::Pause,
+                        reply: None,
+                    };
+                    // Never wait for space here, only this loop empties the channel
+                    match command_tx.try_send(command) {
                         Ok(_) => {
                             info!("Enqueued pending single oneshot pause");
-                            shared_state.single_oneshot.store(false, Ordering::SeqCst);
+                            set_single(&shared_state, false);
                         }
                         Err(e) => error!("Enqueuing pending single oneshot pause failed: {e}"),
                     }
                 }
-                poll_delay = fast_poll_delay;
+                poll_delay = settings.fast_poll_delay;
+            } else if signals {
+                // Changes arrive as signals, polling only covers players that do not send them
+                poll_delay = settings.fallback_poll_delay;
             } else {
-                poll_delay = slow_poll_delay;
-            }
-            try_set_player_state(&shared_state.player_state, state, &mut last_emitted_player_state);
-            // If this player is not playing, need to check if another is
-            if playback_status != mpris::PlaybackStatus::Playing {
-                if let Ok(new_player) = find_mpris_player() {
-                    if new_player.unique_name() != player.unique_name() &&
-                        Some(mpris::PlaybackStatus::Playing) == new_player.get_playback_status().ok()
-                    {
-                        info!("Switching active player to {new_player:?}");
-                        player = new_player;
-                    }
+                poll_delay = settings.slow_poll_delay;
+            }
+            // Signals do not postpone the fallback poll
+            next_poll = match polling {
+                true => Instant::now() + poll_delay,
+                false => next_poll.min(Instant::now() + poll_delay),
+            };
+            try_set_player_state(&shared_state, state, &mut last_emitted_player_state);
+            let policy = &settings.selection;
+            if selection.pinned.is_none() && !policy.is_allowed(&player) {
+                info!("Player {} is no longer allowed", player.bus_name());
+                break;
+            }
+            let switching = selection.pinned.is_none() && settings.switch_mode != SwitchMode::Pinned;
+            let exclusive_enabled = settings.exclusive_playback || settings.auto_resume;
+            // Ducked players are restored even after ducking was disabled by a reload
+            let ducking_enabled = !settings.duck_foreground.is_empty() || ducking.is_active();
+            if signals {
+                let partitions = partition_players.values().map(|player| player.unique_name().to_string()).collect();
+                pending_changes.watch(Some(player.unique_name()), partitions, switching || exclusive_enabled || ducking_enabled);
+            }
+            let outputs_due = names_changed || last_outputs_refresh.elapsed() >= settings.fail_delay;
+            // Only look at all players when one of them may have started or stopped
+            let players_changed = polling || names_changed || bridged_status_changed || changes.others;
+            names_changed = false;
+            if !(outputs_due || (players_changed && (switching || exclusive_enabled || ducking_enabled))) {
+                continue;
+            }
+            // Everything below looks at all players, so ask the bus only once
+            let mut players = match selection::find_players() {
+                Ok(players) => players,
+                Err(e) => {
+                    warn!("Cannot list players. {e}");
+                    continue;
+                }
+            };
+            others_playing = players.iter()
+                .any(|found| found.status == Some(mpris::PlaybackStatus::Playing) && found.player.unique_name() != player.unique_name());
+            // Check whether another player should take over, unless the user selected this one
+            let mut switch_to = None;
+            if switching {
+                let candidates: Vec<&FoundPlayer> = players.iter()
+                    .filter(|found| is_candidate(&selection, policy, *found))
+                    .collect();
+                switch.update(&candidates);
+                switch_to = switch.choose(&player, &candidates, policy, settings.switch_mode, settings.switch_delay)
+                    .and_then(|i| players.iter().position(|found| found.player.unique_name() == candidates[i].player.unique_name()));
+                // Check again once a player has been playing long enough to take over
+                if let Some(settle) = switch.next_settle(settings.switch_delay) {
+                    next_poll = next_poll.min(Instant::now() + settle);
                 }
             }
+            if exclusive_enabled {
+                let resume = settings.auto_resume.then_some(settings.resume_rewind);
+                exclusive.check(&players, &settings.exclusive_exempt, &settings.duck_foreground, resume);
+            }
+            if ducking_enabled && ducking.check(&players, &settings.duck_foreground, settings.duck_level, settings.duck_ramp) {
+                next_poll = next_poll.min(Instant::now() + settings.fast_poll_delay);
+            }
+            if outputs_due || switch_to.is_some() {
+                let bridged = switch_to.map(|i| &players[i].player).unwrap_or(&player);
+                refresh_outputs(&shared_state, Some(bridged), &players, &mut selection);
+                last_outputs_refresh = Instant::now();
+            }
+            if let Some(i) = switch_to {
+                let new_player = players.swap_remove(i).player;
+                info!("Switching active player to {new_player:?}");
+                switch.switched(&player, &new_player);
+                player = new_player;
+                notify_bridged_player(&shared_state, &player);
+                reread = true;
+            }
         };
     }
+    // Leave no player ducked behind
+    if ducking.is_active() {
+        match selection::find_players() {
+            Ok(players) => ducking.restore_all(&players),
+            Err(e) => warn!("Cannot restore ducked volumes. {e}"),
+        }
+    }
 }
 
-async fn handle_mpd_queries(
-    socket: &mut TcpStream,
+async fn handle_mpd_queries<S: MpdSocket>(
+    socket: &mut S,
     commands: &[u8],
     state: &mut MpdQueryState,
     shared_state: Arc<MpdSharedState>,
@@ -360,28 +1655,28 @@ async fn handle_mpd_queries(
         }
         match handle_mpd_query(&remainder, state, shared_state.clone(), socket).await {
             Ok(response) => {
-                if response.len() > 0 {
+                if !response.is_empty() {
                     trace!("Respond {}", safe_command_print(&response));
                     socket.write_all(&response).await?;
                 }
                 if state.in_command_list_ok && !state.command_list_ended {
                     if state.command_list_count > 0 {
                         trace!("Respond list_OK");
-                        socket.write_all(&b"list_OK\n".to_vec()).await?;
+                        socket.write_all(b"list_OK\n").await?;
                     }
                 } else if state.should_close {
                     debug!("Closing the socket per request");
                     return Ok(());
                 } else {
                     trace!("Respond OK");
-                    socket.write_all(&b"OK\n".to_vec()).await?;
+                    socket.write_all(b"OK\n").await?;
                 }
             }
             Err(e) => {
                 warn!("Handling MPD query failed. {}", e);
-                let error_response = format!("ACK [{}@{}] {} {}\n", e.mpd_error_code, e.command_str, state.command_list_count, e);
+                let error_response = format!("ACK [{}@{}] {{{}}} {}\n", e.mpd_error_code, state.command_list_count, e.command_str, e.message);
                 trace!("Respond {}", error_response);
-                socket.write_all(&error_response.as_bytes()).await?;
+                socket.write_all(error_response.as_bytes()).await?;
                 break;
             }
         }
@@ -398,11 +1693,11 @@ async fn handle_mpd_queries(
 }
 
 /// Execute a query and returns the response to send back
-async fn handle_mpd_query(
+async fn handle_mpd_query<S: MpdSocket>(
     command: &[u8],
     state: &mut MpdQueryState,
     shared_state: Arc<MpdSharedState>,
-    socket: &mut TcpStream
+    socket: &mut S
 ) -> Result<Vec<u8>, MpdCommandError> {
     let (command, arguments) = match command.iter().position(|&b| b == b' ') {
         Some(i) => (&command[0..i], &command[i+1..command.len()]),
@@ -413,40 +1708,63 @@ async fn handle_mpd_query(
         debug!("Ignore list command while in failed state: {}", safe_command_print(command));
         return Ok(Vec::new())
     };
+    let required_permissions = command_permissions(command);
+    if !state.permissions.contains(required_permissions) {
+        let safe_command = safe_command_print(command);
+        info!("Denied command {safe_command} with permissions {}", state.permissions);
+        return Err(MpdCommandError::with_code(
+            command,
+            &format!("you don't have permission for \"{safe_command}\""),
+            ACK_ERROR_PERMISSION,
+        ));
+    }
     let result = match command {
         // Health/static commands
         b"ping" => handle_ping(),
-        b"commands" => handle_commands(),
+        b"commands" => handle_commands(state, true),
+        b"notcommands" => handle_commands(state, false),
+        b"password" => handle_password(arguments, state, shared_state),
         b"tagtypes" => handle_tagtypes(),
         // Playback
-        b"play" => handle_play(state).await,
+        b"play" => handle_play(state, &shared_state).await,
         b"pause" => {
             match arguments {
-                b"1" => handle_pause(state).await,
-                b"\"1\"" => handle_pause(state).await,
-                b"" => handle_pause(state).await,
+                b"1" => handle_pause(state, &shared_state).await,
+                b"\"1\"" => handle_pause(state, &shared_state).await,
+                b"" => handle_pause(state, &shared_state).await,
                 _ => {
                     debug!("Pause command with arguments {} mapped to play", safe_command_print(arguments));
-                    handle_play(state).await
+                    handle_play(state, &shared_state).await
                 }
             }
         }
-        b"stop" => {
-            // Some clients don't properly support stop, in which case pause is good enough
-            match handle_stop(state).await {
-                Err(e) => {
-                    warn!("Handling stop failed, try with pause instead: {:?}", e);
-                    handle_pause(state).await
-                }
-                v => v
-            }
-        }
-        b"next" => handle_next(state).await,
-        b"previous" => handle_previous(state).await,
+        b"stop" => handle_stop(state, &shared_state).await,
+        b"next" => handle_next(state, &shared_state).await,
+        b"previous" => handle_previous(state, &shared_state).await,
         b"single" => handle_single(arguments, shared_state),
         // Infos
-        b"currentsong" => handle_current_song(shared_state),
-        b"status" => handle_status(shared_state),
+        b"currentsong" => handle_current_song(state, shared_state),
+        b"status" => handle_status(state, shared_state),
+        b"history" => handle_history(arguments, shared_state),
+        b"stats" => handle_stats(shared_state),
+        // Stickers
+        b"sticker" => handle_sticker(arguments, shared_state),
+        b"stickernames" => handle_stickernames(shared_state),
+        b"stickertypes" => handle_stickertypes(),
+        // Client to client communication
+        b"subscribe" => handle_subscribe(arguments, state, shared_state),
+        b"unsubscribe" => handle_unsubscribe(arguments, state, shared_state),
+        b"channels" => handle_channels(shared_state),
+        b"readmessages" => handle_readmessages(state, shared_state),
+        b"sendmessage" => handle_sendmessage(arguments, state, shared_state),
+        // Players as outputs
+        b"outputs" => handle_outputs(shared_state),
+        b"enableoutput" => handle_enableoutput(arguments, shared_state),
+        b"disableoutput" => handle_disableoutput(arguments, shared_state),
+        b"toggleoutput" => handle_toggleoutput(arguments, shared_state),
+        // Players as partitions
+        b"partition" => handle_partition(arguments, state, shared_state),
+        b"listpartitions" => handle_listpartitions(shared_state),
         b"idle" => handle_idle(arguments, state, shared_state, socket).await,
         // Aggregating commands
         b"command_list_begin" => {
@@ -469,7 +1787,6 @@ async fn handle_mpd_query(
         b"playlistinfo" => handle_dummy("playlistinfo", arguments),
         b"repeat" => handle_dummy("repeat", arguments),
         b"lsinfo" => handle_dummy("lsinfo", arguments),
-        b"stats" => handle_dummy("stats", arguments),
         b"close" => {
             state.should_close = true;
             Ok(Vec::new())
@@ -480,14 +1797,71 @@ async fn handle_mpd_query(
         b"noidle" => handle_dummy("noidle", arguments),
         _ => handle_unknown_command(command)
     };
-    result.map_err(|e|
-        MpdCommandError::new(command, &format!("{:?}", e))
-    )
+    result.map_err(|e| match e.downcast_ref::<MpdAck>() {
+        Some(ack) => MpdCommandError::with_code(command, &ack.message, ack.code),
+        None => MpdCommandError::new(command, &format!("{:?}", e)),
+    })
+}
+
+fn matches_bus_name(player: &Player, bus_name: &str) -> bool {
+    player.bus_name() == bus_name || partition_name(player.bus_name()) == bus_name
+}
+
+fn find_player_by_bus_name(bus_name: &str) -> anyhow::Result<Player> {
+    PlayerFinder::new()?
+        .find_all()?
+        .into_iter()
+        .find(|player| matches_bus_name(player, bus_name))
+        .ok_or_else(|| anyhow::anyhow!("Selected player {bus_name} not found"))
 }
 
-fn find_mpris_player() -> anyhow::Result<Player> {
-    let player = PlayerFinder::new()?.find_active()?;
-    Ok(player)
+/// Whether a player may be selected automatically
+fn is_candidate(selection: &PlayerSelection, policy: &SelectionPolicy, player: &impl PlayerInfo) -> bool {
+    !selection.disabled.contains(player.bus_name()) && policy.is_allowed(player)
+}
+
+/// Index of the player to bridge
+fn find_mpris_player(players: &[FoundPlayer], selection: &PlayerSelection, policy: &SelectionPolicy) -> anyhow::Result<usize> {
+    if let Some(pinned) = &selection.pinned {
+        return players.iter()
+            .position(|found| matches_bus_name(&found.player, pinned))
+            .ok_or_else(|| anyhow::anyhow!("Selected player {pinned} not found"));
+    }
+    // Same preference as PlayerFinder::find_active(), which cannot skip disabled players,
+    // with the configured priority deciding between players in the same state
+    players.iter()
+        .enumerate()
+        .filter(|(_, found)| is_candidate(selection, policy, *found))
+        // Rank each player once, the metadata is read from the bus
+        .map(|(i, found)| {
+            let state_rank = match found.status {
+                Some(mpris::PlaybackStatus::Playing) => 0,
+                Some(mpris::PlaybackStatus::Paused) => 1,
+                _ if found.player.get_metadata().map(|m| !m.is_empty()).unwrap_or(false) => 2,
+                _ => 3,
+            };
+            (i, (state_rank, policy.priority(found)))
+        })
+        .min_by_key(|(_, rank)| *rank)
+        .map(|(i, _)| i)
+        .ok_or_else(|| anyhow::anyhow!("No enabled MPRIS player found"))
+}
+
+/// Go back to the player bridged before an automatic switch, once the player that took over is gone
+fn restore_previous_player(
+    players: &[FoundPlayer],
+    switch: &mut SwitchState,
+    selection: &PlayerSelection,
+    policy: &SelectionPolicy,
+) -> Option<usize> {
+    if selection.pinned.is_some() {
+        return None;
+    }
+    let previous = switch.take_previous()?;
+    let i = players.iter()
+        .position(|found| found.player.bus_name() == previous && is_candidate(selection, policy, found))?;
+    info!("Restoring previously bridged player {previous}");
+    Some(i)
 }
 
 fn handle_ping() -> anyhow::Result<Vec<u8>> {
@@ -495,27 +1869,79 @@ fn handle_ping() -> anyhow::Result<Vec<u8>> {
     Ok(Vec::new())
 }
 
-fn handle_commands() -> anyhow::Result<Vec<u8>> {
-    debug!("Returning supported commands");
-    Ok("command: close\n\
-        command: commands\n\
-        command: currentsong\n\
-        command: getvol\n\
-        command: idle\n\
-        command: lsinfo\n\
-        command: next\n\
-        command: pause\n\
-        command: ping\n\
-        command: play\n\
-        command: playlistinfo\n\
-        command: previous\n\
-        command: setvol\n\
-        command: single\n\
-        command: stats\n\
-        command: status\n\
-        command: stop\n\
-        command: tagtypes\n\
-        command: volume\n".into())
+/// Supported commands and the permissions they require, in the order reported by the commands command
+const COMMAND_PERMISSIONS: &[(&str, Permissions)] = &[
+    ("channels", Permissions::READ),
+    ("close", Permissions::NONE),
+    ("commands", Permissions::NONE),
+    ("currentsong", Permissions::READ),
+    ("disableoutput", Permissions::ADMIN),
+    ("enableoutput", Permissions::ADMIN),
+    ("getvol", Permissions::READ),
+    ("history", Permissions::READ),
+    ("idle", Permissions::READ),
+    ("listpartitions", Permissions::READ),
+    ("lsinfo", Permissions::READ),
+    ("next", Permissions::CONTROL),
+    ("noidle", Permissions::READ),
+    ("notcommands", Permissions::NONE),
+    ("outputs", Permissions::READ),
+    ("partition", Permissions::READ),
+    ("password", Permissions::NONE),
+    ("pause", Permissions::CONTROL),
+    ("ping", Permissions::NONE),
+    ("play", Permissions::CONTROL),
+    ("playlistinfo", Permissions::READ),
+    ("previous", Permissions::CONTROL),
+    ("readmessages", Permissions::READ),
+    ("repeat", Permissions::CONTROL),
+    ("sendmessage", Permissions::CONTROL),
+    ("setvol", Permissions::CONTROL),
+    ("single", Permissions::CONTROL),
+    ("stats", Permissions::READ),
+    ("status", Permissions::READ),
+    ("sticker", Permissions::ADMIN),
+    ("stickernames", Permissions::ADMIN),
+    ("stickertypes", Permissions::READ),
+    ("stop", Permissions::CONTROL),
+    ("subscribe", Permissions::READ),
+    ("tagtypes", Permissions::READ),
+    ("toggleoutput", Permissions::ADMIN),
+    ("unsubscribe", Permissions::READ),
+    ("volume", Permissions::CONTROL),
+];
+
+fn command_permissions(command: &[u8]) -> Permissions {
+    COMMAND_PERMISSIONS.iter()
+        .find(|(name, _)| name.as_bytes() == command)
+        .map(|(_, permissions)| *permissions)
+        // Command lists and unknown commands are handled without touching any state
+        .unwrap_or(Permissions::NONE)
+}
+
+/// List the commands the connection is allowed to use, or the ones it is not allowed to use
+fn handle_commands(state: &MpdQueryState, allowed: bool) -> anyhow::Result<Vec<u8>> {
+    debug!("Returning supported commands, allowed: {allowed}");
+    let mut response: Vec<u8> = Vec::new();
+    for (name, permissions) in COMMAND_PERMISSIONS {
+        if state.permissions.contains(*permissions) == allowed {
+            response.append(&mut format!("command: {name}\n").into());
+        }
+    }
+    Ok(response)
+}
+
+fn handle_password(arguments: &[u8], state: &mut MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let [password] = parse_arguments(arguments)?.try_into()
+        .map_err(|_| mpd_ack(ACK_ERROR_ARG, "Expected exactly one password"))?;
+    let settings = shared_state.settings();
+    let Some(entry) = settings.passwords.iter().find(|entry| entry.password == password) else {
+        info!("Rejected incorrect password");
+        return Err(mpd_ack(ACK_ERROR_PASSWORD, "incorrect password"));
+    };
+    state.permissions = entry.permissions & state.permission_limit;
+    info!("Authenticated with permissions {}", state.permissions);
+    Ok(Vec::new())
 }
 
 fn handle_tagtypes() -> anyhow::Result<Vec<u8>> {
@@ -526,46 +1952,70 @@ fn handle_tagtypes() -> anyhow::Result<Vec<u8>> {
 }
 
 
-async fn handle_play(state: &mut MpdQueryState) -> anyhow::Result<Vec<u8>> {
-    state.command_tx.send(Command::Play).await?;
+/// Send a command to the player and wait until it was executed
+async fn send_command(state: &MpdQueryState, shared_state: &MpdSharedState, command: Command) -> anyhow::Result<()> {
+    let settings = shared_state.settings();
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let command = PlayerCommand {
+        partition: state.partition.clone(),
+        command,
+        reply: Some(reply_tx),
+    };
+    timeout(settings.command_timeout, state.command_tx.send(command)).await
+        .map_err(|_| mpd_ack(ACK_ERROR_SYSTEM, "Player is not responding"))??;
+    // Commands may wait for a player to appear before they are executed
+    timeout(settings.command_ttl + settings.command_timeout, reply_rx).await
+        .map_err(|_| mpd_ack(ACK_ERROR_SYSTEM, "Player is not responding"))?
+        .map_err(|_| mpd_ack(ACK_ERROR_SYSTEM, "Command was dropped"))?
+}
+
+async fn handle_play(state: &mut MpdQueryState, shared_state: &MpdSharedState) -> anyhow::Result<Vec<u8>> {
+    send_command(state, shared_state, Command::Play).await?;
     debug!("Ack play action");
     Ok(Vec::new())
 }
 
-async fn handle_pause(state: &mut MpdQueryState) -> anyhow::Result<Vec<u8>> {
-    state.command_tx.send(Command::Pause).await?;
+async fn handle_pause(state: &mut MpdQueryState, shared_state: &MpdSharedState) -> anyhow::Result<Vec<u8>> {
+    send_command(state, shared_state, Command::Pause).await?;
     debug!("Ack pause action");
     Ok(Vec::new())
 }
 
-async fn handle_stop(state: &mut MpdQueryState) -> anyhow::Result<Vec<u8>> {
-    state.command_tx.send(Command::Stop).await?;
+async fn handle_stop(state: &mut MpdQueryState, shared_state: &MpdSharedState) -> anyhow::Result<Vec<u8>> {
+    send_command(state, shared_state, Command::Stop).await?;
     debug!("Ack stop action");
     Ok(Vec::new())
 }
 
-async fn handle_next(state: &mut MpdQueryState) -> anyhow::Result<Vec<u8>> {
-    state.command_tx.send(Command::Next).await?;
+async fn handle_next(state: &mut MpdQueryState, shared_state: &MpdSharedState) -> anyhow::Result<Vec<u8>> {
+    send_command(state, shared_state, Command::Next).await?;
     debug!("Ack next action");
     Ok(Vec::new())
 }
 
-async fn handle_previous(state: &mut MpdQueryState) -> anyhow::Result<Vec<u8>> {
-    state.command_tx.send(Command::Prev).await?;
+async fn handle_previous(state: &mut MpdQueryState, shared_state: &MpdSharedState) -> anyhow::Result<Vec<u8>> {
+    send_command(state, shared_state, Command::Prev).await?;
     debug!("Ack prev action");
     Ok(Vec::new())
 }
 
+/// Clients see the single mode as part of the player state
+fn set_single(shared_state: &MpdSharedState, single: bool) {
+    if shared_state.single_oneshot.swap(single, Ordering::SeqCst) != single {
+        shared_state.idle.notify(Subsystems::PLAYER, IdleScope::All);
+    }
+}
+
 fn handle_single(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
-    let arguments = std::str::from_utf8(&arguments)?;
+    let arguments = std::str::from_utf8(arguments)?;
     debug!("Handling single: {arguments}");
     match arguments {
         "0" | "\"0\"" => {
-            shared_state.single_oneshot.store(false, Ordering::SeqCst);
+            set_single(&shared_state, false);
             Ok(Vec::new())
         }
         "1" | "\"1\"" | "oneshot" | "\"oneshot\"" => {
-            shared_state.single_oneshot.store(true, Ordering::SeqCst);
+            set_single(&shared_state, true);
             Ok(Vec::new())
         }
         _ => {
@@ -575,20 +2025,45 @@ fn handle_single(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow:
     }
 }
 
-fn handle_current_song(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
-    let Ok(player_state) = shared_state.player_state.read() else {
-        error!("Failed to read player state for current song");
-        return Ok(Vec::new());
-    };
-    let Some(ref player_state) = *player_state else {
+/// State of the player the partition refers to, the bridged player for the default partition
+fn get_player_state(shared_state: &MpdSharedState, partition: Option<&str>) -> Option<PlayerState> {
+    match partition {
+        None => match shared_state.player_state.read() {
+            Ok(player_state) => player_state.clone(),
+            Err(_) => {
+                error!("Failed to read player state");
+                None
+            }
+        },
+        Some(partition) => match shared_state.partition_states.read() {
+            Ok(partition_states) => partition_states.get(partition).cloned(),
+            Err(_) => {
+                error!("Failed to read partition states");
+                None
+            }
+        },
+    }
+}
+
+fn handle_current_song(state: &MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let Some(player_state) = get_player_state(&shared_state, state.partition.as_deref()) else {
         info!("Handled current song without player");
         return Ok(Vec::new());
     };
     let mut response: Vec<u8> = Vec::new();
 
-    if let Some(title) = &player_state.title {
-        response.append(&mut format!("file: {title}\nTitle: {title}\n").into());
-    };
+    if state.metadata_style == MetadataStyle::Legacy {
+        if let Some(title) = &player_state.title {
+            response.append(&mut format!("file: {title}\nTitle: {title}\n").into());
+        };
+    } else {
+        if let Some(uri) = player_state.uri() {
+            response.append(&mut format!("file: {uri}\n").into());
+        };
+        if let Some(title) = &player_state.title {
+            response.append(&mut format!("Title: {title}\n").into());
+        };
+    }
     if let Some(artist) = &player_state.artist {
         response.append(&mut format!("Artist: {artist}\n").into());
     };
@@ -599,11 +2074,88 @@ fn handle_current_song(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<
         response.append(&mut format!("arturl: {art_url}\n").into());
     };
     debug!("Handled current song with player state {:?}", player_state);
-    Ok(response.into())
+    Ok(response)
+}
+
+fn handle_history(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let arguments = std::str::from_utf8(arguments)?;
+    debug!("Handling history: {arguments}");
+    let arguments = arguments.replace("\"", "");
+    let limit = match arguments.as_str() {
+        "" => None,
+        limit => Some(limit.parse::<usize>()?),
+    };
+    let Ok(history) = shared_state.history.lock() else {
+        error!("Failed to lock history");
+        return Err(anyhow::anyhow!("History not available"));
+    };
+    let entries = history.entries();
+    let skip = limit.map(|limit| entries.len().saturating_sub(limit)).unwrap_or(0);
+    let mut response: Vec<u8> = Vec::new();
+    for entry in &entries[skip..] {
+        if let Some(uri) = track_uri(&entry.title, &entry.artist, &entry.album, &entry.url) {
+            response.append(&mut format!("file: {uri}\n").into());
+        }
+        if let Some(title) = &entry.title {
+            response.append(&mut format!("Title: {title}\n").into());
+        }
+        if let Some(artist) = &entry.artist {
+            response.append(&mut format!("Artist: {artist}\n").into());
+        }
+        if let Some(album) = &entry.album {
+            response.append(&mut format!("Album: {album}\n").into());
+        }
+        if let Some(duration) = entry.duration {
+            response.append(&mut format!("duration: {duration:.3}\n").into());
+        }
+        response.append(&mut format!(
+            "player: {}\n\
+             started: {}\n\
+             ended: {}\n\
+             listened: {:.3}\n\
+             skipped: {}\n",
+            entry.player_identity,
+            entry.started_at,
+            entry.ended_at,
+            entry.listened,
+            entry.skipped as u8,
+        ).into());
+    }
+    Ok(response)
+}
+
+fn handle_stats(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let uptime = shared_state.started_at.elapsed().as_secs();
+    let playtime = shared_state.playtime_ms.load(Ordering::SeqCst) / 1000;
+    let mut response: Vec<u8> = format!("uptime: {uptime}\nplaytime: {playtime}\n").into();
+    let stats = match shared_state.history.lock() {
+        Ok(history) => history.stats(),
+        Err(_) => {
+            error!("Failed to lock history for stats");
+            None
+        }
+    };
+    if let Some(stats) = stats {
+        response.append(&mut format!(
+            "artists: {}\n\
+             albums: {}\n\
+             songs: {}\n\
+             db_playtime: {}\n\
+             db_update: {}\n",
+            stats.artists,
+            stats.albums,
+            stats.songs,
+            stats.db_playtime,
+            stats.db_update,
+        ).into());
+    }
+    debug!("Handled stats: uptime {uptime}, playtime {playtime}");
+    Ok(response)
 }
 
-fn handle_dummy_status(volume: u8) -> Vec<u8> {
-    format!("repeat: 0\n\
+fn handle_dummy_status(volume: u8, partition: &str) -> Vec<u8> {
+    format!("partition: {partition}\n\
+             repeat: 0\n\
              random: 0\n\
              song: 0\n\
              playlistlength: 0\n\
@@ -611,15 +2163,12 @@ fn handle_dummy_status(volume: u8) -> Vec<u8> {
              state: stop\n").into()
 }
 
-fn handle_status(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+fn handle_status(query_state: &MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
     let volume = shared_state.null_volume.load(Ordering::SeqCst);
-    let Ok(player_state) = shared_state.player_state.read() else {
-        error!("Failed to read player state for status");
-        return Ok(handle_dummy_status(volume));
-    };
-    let Some(ref player_state) = *player_state else {
+    let partition = query_state.partition.as_deref().unwrap_or(DEFAULT_PARTITION);
+    let Some(player_state) = get_player_state(&shared_state, query_state.partition.as_deref()) else {
         info!("Handled status without player");
-        return Ok(handle_dummy_status(volume));
+        return Ok(handle_dummy_status(volume, partition));
     };
 
     // https://mpd.readthedocs.io/en/latest/protocol.html
@@ -636,7 +2185,8 @@ fn handle_status(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
 
     let response: &mut Vec<u8> =
         &mut format!(
-            "repeat: 0\n\
+            "partition: {partition}\n\
+             repeat: 0\n\
              random: 0\n\
              song: 0\n\
              playlistlength: 1\n\
@@ -648,7 +2198,7 @@ fn handle_status(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
     if let Some(duration) = player_state.duration {
         response.append(&mut format!("duration: {duration}\n").into());
     };
-    if let Some(elapsed) = player_state.elapsed {
+    if let Some(elapsed) = player_state.current_elapsed() {
         response.append(&mut format!("elapsed: {elapsed}\n").into());
         if let Some(duration) = player_state.duration {
             response.append(&mut format!("time: {elapsed:.0}:{duration:.0}\n").into());
@@ -661,14 +2211,13 @@ fn handle_status(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
     Ok(response.to_vec())
 }
 
-fn get_state_for_idle_player(player_state: &PlayerState, shared_state: &MpdSharedState) -> PlayerStateForIdle {
+fn get_state_for_idle_player(player_state: &PlayerState) -> PlayerStateForIdle {
     // Just a subset of values interesting for the idle command
     PlayerStateForIdle {
         playback_status: player_state.playback_status,
         title: player_state.title.clone(),
         artist: player_state.artist.clone(),
         art_url: player_state.art_url.clone(),
-        single: shared_state.single_oneshot.load(Ordering::SeqCst),
     }
 }
 
@@ -680,106 +2229,105 @@ fn get_state_for_idle_playlist(player_state: &PlayerState) -> PlayerState {
         artist: player_state.artist.clone(),
         duration: None,
         elapsed: None,
+        elapsed_at: None,
         art_url: None,
+        album: None,
+        url: None,
+    }
+}
+
+/// Subsystems whose idle relevant values differ between two player states
+fn get_idle_changes(old: Option<&PlayerState>, new: Option<&PlayerState>) -> Subsystems {
+    let mut changed = Subsystems::NONE;
+    if old.map(get_state_for_idle_player) != new.map(get_state_for_idle_player) {
+        changed |= Subsystems::PLAYER;
     }
+    if old.map(get_state_for_idle_playlist) != new.map(get_state_for_idle_playlist) {
+        changed |= Subsystems::PLAYLIST;
+    }
+    changed
 }
 
 fn get_state_for_single_oneshot(player_state: &PlayerState) -> (Option<String>, Option<String>) {
     (player_state.title.clone(), player_state.artist.clone())
 }
 
-async fn handle_idle(
+async fn handle_idle<S: MpdSocket>(
     arguments: &[u8],
     state: &mut MpdQueryState,
     shared_state: Arc<MpdSharedState>,
-    socket: &mut TcpStream
+    socket: &mut S
 ) -> anyhow::Result<Vec<u8>> {
-    let arguments = std::str::from_utf8(&arguments)?;
-    let idle_all = arguments.len() == 0;
-    let idle_player = idle_all || arguments.contains("\"player\"") || arguments.contains("player");
-    let idle_playlist = idle_all || arguments.contains("\"playlist\"") || arguments.contains("playlist");
-    let idle_mixer = idle_all || arguments.contains("\"mixer\"") || arguments.contains("mixer");
-    if !idle_player && !idle_mixer && !idle_playlist {
-        return Err(anyhow::anyhow!("No supported subsystem in {}", arguments));
-    }
-    debug!("Handling idle... subsystems: {}", arguments);
-    let sleep_duration = Duration::from_millis(333);
+    let arguments = parse_arguments(arguments)?;
+    let subsystems = Subsystems::from_idle_arguments(&arguments);
+    if subsystems.is_empty() {
+        return Err(anyhow::anyhow!("No supported subsystem in {}", arguments.join(" ")));
+    }
+    debug!("Handling idle... subsystems: {}", subsystems);
+    let mut shutdown = shared_state.shutdown.clone();
+    let mut buf = [0; 1024];
     loop {
-        if idle_player || idle_playlist {
-            let current_raw_state = shared_state.player_state
-                .read()
-                .ok()
-                .map(|inner| inner.clone())
-                .flatten();
-            if idle_player {
-                let current_state = current_raw_state.as_ref().map(|state| get_state_for_idle_player(state, &shared_state));
-                if current_state != state.last_idle_player_state {
-                    info!("Handling idle finished with player status change");
-                    state.last_idle_player_state = current_state;
-                    return Ok(b"changed: player\n".to_vec());
-                }
-            }
-            if idle_playlist {
-                let current_state = current_raw_state.as_ref().map(|state| get_state_for_idle_playlist(state));
-                if current_state != state.last_idle_playlist_state {
-                    info!("Handling idle finished with playlist status change");
-                    state.last_idle_playlist_state = current_state;
-                    return Ok(b"changed: playlist\n".to_vec());
-                }
-            }
+        // Changes since the last idle count too, like in MPD
+        let changed = state.idle.take(subsystems);
+        if !changed.is_empty() {
+            debug!("Handling idle finished with changes: {changed}");
+            return Ok(changed.names().map(|name| format!("changed: {name}\n")).collect::<String>().into());
+        }
+        if *shutdown.borrow() {
+            debug!("Closing idle connection for shutdown");
+            state.should_close = true;
+            return Ok(Vec::new());
         }
-        if idle_mixer {
-            let current_volume = Some(shared_state.null_volume.load(Ordering::SeqCst));
-            if current_volume != state.last_idle_mixer_state {
-                debug!("Handling idle finished with mixer status change");
-                state.last_idle_mixer_state = current_volume;
-                return Ok(b"changed: mixer\n".to_vec());
-            }
-        }
-        let mut buf = [0; 1024];
-        let mut read: Vec<u8> = Vec::new();
-        match timeout(sleep_duration, socket.read(&mut buf)).await {
-            Ok(Ok(0)) => {
-                debug!("Socket closed from idle");
-                state.should_close = true;
-                return Ok(Vec::new());
-            }
-            Ok(Ok(n)) => {
-                read.append(&mut buf[0..n].to_vec());
-                if let Some(i) = read.iter().position(|&b| b == b'\n' || b == b'\r') {
-                    if &read[0..i] == b"noidle" {
-                        debug!("Finish idle early due to noidle command");
-                        return Ok(Vec::new());
+        tokio::select! {
+            _ = state.idle.wait() => {}
+            _ = shutdown.changed() => {}
+            result = socket.read(&mut buf) => match result {
+                Ok(0) => {
+                    debug!("Socket closed from idle");
+                    state.should_close = true;
+                    return Ok(Vec::new());
+                }
+                Ok(n) => {
+                    if let Some(i) = buf[0..n].iter().position(|&b| b == b'\n' || b == b'\r') {
+                        if &buf[0..i] == b"noidle" {
+                            debug!("Finish idle early due to noidle command");
+                            return Ok(Vec::new());
+                        }
                     }
                 }
-            }
-            Ok(Err(e)) => {
-                error!("Failed to read while idling: {e}");
-                return Err(e.into());
-            }
-            Err(_) => {} // Just a timeout for idle state polling
+                Err(e) => {
+                    error!("Failed to read while idling: {e}");
+                    return Err(e.into());
+                }
+            },
         }
     }
 }
 
+fn set_volume(shared_state: &MpdSharedState, volume: u8) {
+    if shared_state.null_volume.swap(volume, Ordering::SeqCst) != volume {
+        shared_state.idle.notify(Subsystems::MIXER, IdleScope::All);
+    }
+}
+
 fn handle_volume(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
-    let arguments = std::str::from_utf8(&arguments)?;
+    let arguments = std::str::from_utf8(arguments)?;
     debug!("Handling volume: {arguments}");
     let arguments = arguments.replace("\"", "");
     // Only allow u8 volume changes, but use bigger type for calculation without overflows
     let volume_change = arguments.parse::<i8>()? as i16;
     let volume = shared_state.null_volume.load(Ordering::SeqCst) as i16;
-    let volume = (volume + volume_change).min(100).max(0) as u8;
-    shared_state.null_volume.store(volume, Ordering::SeqCst);
+    let volume = (volume + volume_change).clamp(0, 100) as u8;
+    set_volume(&shared_state, volume);
     Ok(Vec::new())
 }
 
 fn handle_setvol(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
-    let arguments = std::str::from_utf8(&arguments)?;
+    let arguments = std::str::from_utf8(arguments)?;
     debug!("Handling setvol: {arguments}");
     let arguments = arguments.replace("\"", "");
     let volume = arguments.parse::<u8>()?;
-    shared_state.null_volume.store(volume, Ordering::SeqCst);
+    set_volume(&shared_state, volume);
     Ok(Vec::new())
 }
 
@@ -789,6 +2337,338 @@ fn handle_getvol(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
     Ok(format!("volume: {volume}\n").into())
 }
 
+/// Split MPD command arguments, honoring double quotes and backslash escapes
+fn parse_arguments(arguments: &[u8]) -> anyhow::Result<Vec<String>> {
+    let arguments = std::str::from_utf8(arguments)?;
+    let mut result = Vec::new();
+    let mut chars = arguments.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(c) = chars.next() else {
+            break;
+        };
+        let mut argument = String::new();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => argument.push(c),
+                        None => return Err(mpd_ack(ACK_ERROR_ARG, "Incomplete escape sequence")),
+                    },
+                    Some(c) => argument.push(c),
+                    None => return Err(mpd_ack(ACK_ERROR_ARG, "Missing closing quote")),
+                }
+            }
+        } else {
+            argument.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                argument.push(c);
+            }
+        }
+        result.push(argument);
+    }
+    Ok(result)
+}
+
+fn handle_sticker(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let arguments = parse_arguments(arguments)?;
+    debug!("Handling sticker: {arguments:?}");
+    let arguments: Vec<&str> = arguments.iter().map(|a| a.as_str()).collect();
+    let (action, sticker_type, uri, rest) = match arguments.as_slice() {
+        [action, sticker_type, uri, rest @ ..] => (*action, *sticker_type, *uri, rest),
+        _ => return Err(mpd_ack(ACK_ERROR_ARG, "Too few arguments for sticker")),
+    };
+    if sticker_type != "song" {
+        return Err(mpd_ack(ACK_ERROR_ARG, &format!("Unknown sticker type: {sticker_type}")));
+    }
+    let Ok(mut stickers) = shared_state.stickers.lock() else {
+        error!("Failed to lock stickers");
+        return Err(anyhow::anyhow!("Sticker database not available"));
+    };
+    match (action, rest) {
+        ("get", [name]) => match stickers.get(uri, name) {
+            Some(value) => Ok(format!("sticker: {name}={value}\n").into()),
+            None => Err(mpd_ack(ACK_ERROR_NO_EXIST, "no such sticker")),
+        },
+        ("set", [name, value]) => {
+            stickers.set(uri, name, value)?;
+            shared_state.idle.notify(Subsystems::STICKER, IdleScope::All);
+            Ok(Vec::new())
+        }
+        ("inc" | "dec", [name, delta @ ..]) => {
+            let delta = match delta {
+                [] => 1,
+                [delta] => delta.parse::<i64>()
+                    .map_err(|_| mpd_ack(ACK_ERROR_ARG, &format!("Invalid number: {delta}")))?,
+                _ => return Err(mpd_ack(ACK_ERROR_ARG, "Too many arguments for sticker")),
+            };
+            let delta = if action == "dec" { -delta } else { delta };
+            stickers.add(uri, name, delta)?;
+            shared_state.idle.notify(Subsystems::STICKER, IdleScope::All);
+            Ok(Vec::new())
+        }
+        ("delete", [] | [_]) => match stickers.delete(uri, rest.first().copied())? {
+            true => {
+                shared_state.idle.notify(Subsystems::STICKER, IdleScope::All);
+                Ok(Vec::new())
+            }
+            false => Err(mpd_ack(ACK_ERROR_NO_EXIST, "no such sticker")),
+        },
+        ("list", []) => {
+            let mut response: Vec<u8> = Vec::new();
+            for (name, value) in stickers.list(uri).into_iter().flatten() {
+                response.append(&mut format!("sticker: {name}={value}\n").into());
+            }
+            Ok(response)
+        }
+        ("find", [name, filter @ ..]) => {
+            let filter: Box<dyn Fn(&str) -> bool> = match filter {
+                [] => Box::new(|_| true),
+                [operator, expected] => {
+                    let expected = expected.to_string();
+                    let expected_number = expected.parse::<i64>().ok();
+                    let compare_number = move |value: &str, f: fn(i64, i64) -> bool| {
+                        match (value.parse::<i64>(), expected_number) {
+                            (Ok(value), Some(expected)) => f(value, expected),
+                            _ => false,
+                        }
+                    };
+                    match *operator {
+                        "=" => Box::new(move |value| value == expected),
+                        "<" => Box::new(move |value| value < expected.as_str()),
+                        ">" => Box::new(move |value| value > expected.as_str()),
+                        "eq" => Box::new(move |value| compare_number(value, |a, b| a == b)),
+                        "lt" => Box::new(move |value| compare_number(value, |a, b| a < b)),
+                        "gt" => Box::new(move |value| compare_number(value, |a, b| a > b)),
+                        "contains" => Box::new(move |value| value.contains(expected.as_str())),
+                        "starts_with" => Box::new(move |value| value.starts_with(expected.as_str())),
+                        _ => return Err(mpd_ack(ACK_ERROR_ARG, &format!("Bad operator: {operator}"))),
+                    }
+                }
+                _ => return Err(mpd_ack(ACK_ERROR_ARG, "Bad sticker find arguments")),
+            };
+            // An empty or root URI matches all tracks
+            let uri_prefix = uri.trim_start_matches('/');
+            let mut response: Vec<u8> = Vec::new();
+            for (uri, value) in stickers.find(uri_prefix, name, filter) {
+                response.append(&mut format!("file: {uri}\nsticker: {name}={value}\n").into());
+            }
+            Ok(response)
+        }
+        _ => Err(mpd_ack(ACK_ERROR_ARG, &format!("Bad arguments for sticker {action}"))),
+    }
+}
+
+fn handle_stickernames(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let Ok(stickers) = shared_state.stickers.lock() else {
+        error!("Failed to lock stickers");
+        return Err(anyhow::anyhow!("Sticker database not available"));
+    };
+    let mut response: Vec<u8> = Vec::new();
+    for name in stickers.names() {
+        response.append(&mut format!("name: {name}\n").into());
+    }
+    Ok(response)
+}
+
+fn handle_stickertypes() -> anyhow::Result<Vec<u8>> {
+    debug!("Returning supported sticker types");
+    Ok("stickertype: song\n".into())
+}
+
+fn channel_ack(e: ChannelError) -> anyhow::Error {
+    let code = match e {
+        ChannelError::InvalidName => ACK_ERROR_ARG,
+        ChannelError::AlreadySubscribed | ChannelError::TooManySubscriptions => ACK_ERROR_EXIST,
+        ChannelError::NotSubscribed | ChannelError::NoReceiver => ACK_ERROR_NO_EXIST,
+    };
+    mpd_ack(code, &e.to_string())
+}
+
+fn handle_subscribe(arguments: &[u8], state: &MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let [channel] = parse_arguments(arguments)?.try_into()
+        .map_err(|_| mpd_ack(ACK_ERROR_ARG, "Expected exactly one channel"))?;
+    debug!("Handling subscribe: {channel}");
+    let Ok(mut channels) = shared_state.channels.lock() else {
+        error!("Failed to lock channels");
+        return Err(anyhow::anyhow!("Channels not available"));
+    };
+    channels.subscribe(state.client_id, &channel).map_err(channel_ack)?;
+    shared_state.idle.notify(Subsystems::SUBSCRIPTION, IdleScope::All);
+    Ok(Vec::new())
+}
+
+fn handle_unsubscribe(arguments: &[u8], state: &MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let [channel] = parse_arguments(arguments)?.try_into()
+        .map_err(|_| mpd_ack(ACK_ERROR_ARG, "Expected exactly one channel"))?;
+    debug!("Handling unsubscribe: {channel}");
+    let Ok(mut channels) = shared_state.channels.lock() else {
+        error!("Failed to lock channels");
+        return Err(anyhow::anyhow!("Channels not available"));
+    };
+    channels.unsubscribe(state.client_id, &channel).map_err(channel_ack)?;
+    shared_state.idle.notify(Subsystems::SUBSCRIPTION, IdleScope::All);
+    Ok(Vec::new())
+}
+
+fn handle_channels(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let Ok(channels) = shared_state.channels.lock() else {
+        error!("Failed to lock channels");
+        return Err(anyhow::anyhow!("Channels not available"));
+    };
+    let mut response: Vec<u8> = Vec::new();
+    for channel in channels.channels() {
+        response.append(&mut format!("channel: {channel}\n").into());
+    }
+    Ok(response)
+}
+
+fn handle_readmessages(state: &MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let Ok(mut channels) = shared_state.channels.lock() else {
+        error!("Failed to lock channels");
+        return Err(anyhow::anyhow!("Channels not available"));
+    };
+    let mut response: Vec<u8> = Vec::new();
+    for (channel, message) in channels.read_messages(state.client_id) {
+        response.append(&mut format!("channel: {channel}\nmessage: {message}\n").into());
+    }
+    Ok(response)
+}
+
+fn handle_sendmessage(arguments: &[u8], state: &MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let [channel, message] = parse_arguments(arguments)?.try_into()
+        .map_err(|_| mpd_ack(ACK_ERROR_ARG, "Expected channel and message"))?;
+    debug!("Handling sendmessage on {channel}: {message}");
+    if channel == BRIDGE_CHANNEL {
+        // Bridge commands change the player selection just like the output commands
+        if !state.permissions.contains(Permissions::ADMIN) {
+            return Err(mpd_ack(ACK_ERROR_PERMISSION, "you don't have permission to control the bridge"));
+        }
+        let Some(command) = BridgeCommand::parse(&message) else {
+            return Err(mpd_ack(ACK_ERROR_ARG, &format!("Unknown bridge command: {message}")));
+        };
+        shared_state.bridge_tx.try_send(command)?;
+        return Ok(Vec::new());
+    }
+    let Ok(mut channels) = shared_state.channels.lock() else {
+        error!("Failed to lock channels");
+        return Err(anyhow::anyhow!("Channels not available"));
+    };
+    let receivers = channels.send_message(&channel, &message).map_err(channel_ack)?;
+    shared_state.idle.notify(Subsystems::MESSAGE, IdleScope::Clients(receivers));
+    Ok(Vec::new())
+}
+
+fn handle_outputs(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let Ok(outputs) = shared_state.outputs.read() else {
+        error!("Failed to read outputs");
+        return Err(anyhow::anyhow!("Outputs not available"));
+    };
+    let mut response: Vec<u8> = Vec::new();
+    for output in outputs.iter() {
+        response.append(&mut format!(
+            "outputid: {}\n\
+             outputname: {}\n\
+             plugin: mpris\n\
+             outputenabled: {}\n\
+             attribute: bus_name={}\n",
+            output.id,
+            output.identity,
+            output.enabled as u8,
+            output.bus_name,
+        ).into());
+    }
+    debug!("Handled outputs: {} players", outputs.len());
+    Ok(response)
+}
+
+fn find_output(arguments: &[u8], shared_state: &MpdSharedState) -> anyhow::Result<OutputInfo> {
+    let [id] = parse_arguments(arguments)?.try_into()
+        .map_err(|_| mpd_ack(ACK_ERROR_ARG, "Expected exactly one output id"))?;
+    let id = id.parse::<u32>()
+        .map_err(|_| mpd_ack(ACK_ERROR_ARG, &format!("Invalid output id: {id}")))?;
+    let Ok(outputs) = shared_state.outputs.read() else {
+        error!("Failed to read outputs");
+        return Err(anyhow::anyhow!("Outputs not available"));
+    };
+    outputs.iter()
+        .find(|output| output.id == id)
+        .cloned()
+        .ok_or_else(|| mpd_ack(ACK_ERROR_NO_EXIST, "No such audio output"))
+}
+
+fn handle_enableoutput(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let output = find_output(arguments, &shared_state)?;
+    debug!("Handling enableoutput for {}", output.bus_name);
+    shared_state.bridge_tx.try_send(BridgeCommand::SelectPlayer(output.bus_name))?;
+    Ok(Vec::new())
+}
+
+fn handle_disableoutput(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let output = find_output(arguments, &shared_state)?;
+    debug!("Handling disableoutput for {}", output.bus_name);
+    shared_state.bridge_tx.try_send(BridgeCommand::DisablePlayer(output.bus_name))?;
+    Ok(Vec::new())
+}
+
+fn handle_toggleoutput(arguments: &[u8], shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let output = find_output(arguments, &shared_state)?;
+    debug!("Handling toggleoutput for {}", output.bus_name);
+    let command = match output.enabled {
+        true => BridgeCommand::DisablePlayer(output.bus_name),
+        false => BridgeCommand::SelectPlayer(output.bus_name),
+    };
+    shared_state.bridge_tx.try_send(command)?;
+    Ok(Vec::new())
+}
+
+/// The default partition plus one partition per visible player
+fn get_partitions(shared_state: &MpdSharedState) -> Vec<String> {
+    let mut partitions = vec![DEFAULT_PARTITION.to_string()];
+    match shared_state.outputs.read() {
+        Ok(outputs) => partitions.extend(outputs.iter().map(|output| partition_name(&output.bus_name).to_string())),
+        Err(_) => error!("Failed to read outputs for partitions"),
+    }
+    partitions
+}
+
+fn handle_listpartitions(shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let mut response: Vec<u8> = Vec::new();
+    for partition in get_partitions(&shared_state) {
+        response.append(&mut format!("partition: {partition}\n").into());
+    }
+    Ok(response)
+}
+
+fn handle_partition(arguments: &[u8], state: &mut MpdQueryState, shared_state: Arc<MpdSharedState>) -> anyhow::Result<Vec<u8>> {
+    let [partition] = parse_arguments(arguments)?.try_into()
+        .map_err(|_| mpd_ack(ACK_ERROR_ARG, "Expected exactly one partition"))?;
+    debug!("Handling partition: {partition}");
+    if state.partition_pinned {
+        return Err(mpd_ack(ACK_ERROR_PERMISSION, "partition is pinned by this listener"));
+    }
+    if !get_partitions(&shared_state).contains(&partition) {
+        return Err(mpd_ack(ACK_ERROR_NO_EXIST, "partition does not exist"));
+    }
+    let Ok(mut bound_partitions) = shared_state.bound_partitions.lock() else {
+        error!("Failed to lock bound partitions");
+        return Err(anyhow::anyhow!("Partitions not available"));
+    };
+    if partition == DEFAULT_PARTITION {
+        bound_partitions.remove(&state.client_id);
+        state.partition = None;
+    } else {
+        bound_partitions.insert(state.client_id, partition.clone());
+        shared_state.partitions_bound.notify_one();
+        state.partition = Some(partition);
+    }
+    state.idle.set_partition(state.partition.clone());
+    // Report the new player's state on the next idle, MPD does the same
+    state.idle.mark(Subsystems::PLAYER | Subsystems::PLAYLIST);
+    Ok(Vec::new())
+}
+
 fn handle_unknown_command(command: &[u8]) -> anyhow::Result<Vec<u8>> {
     let safe_command = safe_command_print(command);
     debug!("Ignoring unknown command: {safe_command}");
@@ -796,9 +2676,65 @@ fn handle_unknown_command(command: &[u8]) -> anyhow::Result<Vec<u8>> {
 }
 
 fn handle_dummy(name: &str, arguments: &[u8]) -> anyhow::Result<Vec<u8>> {
-    match std::str::from_utf8(&arguments) {
+    match std::str::from_utf8(arguments) {
         Ok(arguments) => debug!("Handling dummy action {name} {arguments}"),
         Err(_) => debug!("Handling dummy action {name}"),
     }
     Ok(Vec::new())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bridge_command_parse() {
+        assert!(matches!(BridgeCommand::parse("list-players"), Some(BridgeCommand::ListPlayers)));
+        assert!(matches!(BridgeCommand::parse("  release-player "), Some(BridgeCommand::ReleasePlayer)));
+        assert!(matches!(BridgeCommand::parse("reload"), Some(BridgeCommand::Reload)));
+        assert!(matches!(
+            BridgeCommand::parse("select-player org.mpris.MediaPlayer2.vlc"),
+            Some(BridgeCommand::SelectPlayer(bus_name)) if bus_name == "org.mpris.MediaPlayer2.vlc"
+        ));
+    }
+
+    #[test]
+    fn bridge_command_parse_rejects_invalid() {
+        assert!(BridgeCommand::parse("").is_none());
+        assert!(BridgeCommand::parse("unknown").is_none());
+        assert!(BridgeCommand::parse("select-player").is_none());
+        assert!(BridgeCommand::parse("select-player a b").is_none());
+        assert!(BridgeCommand::parse("list-players now").is_none());
+        assert!(BridgeCommand::parse("reload config").is_none());
+    }
+
+    #[test]
+    fn track_uri_prefers_url() {
+        let some = |value: &str| Some(value.to_string());
+        assert_eq!(track_uri(&some("Title"), &None, &None, &some("file:///a.mp3")), some("file:///a.mp3"));
+        assert_eq!(track_uri(&None, &None, &None, &None), None);
+    }
+
+    #[test]
+    fn track_uri_hashes_metadata() {
+        let some = |value: &str| Some(value.to_string());
+        let uri = track_uri(&some("Title"), &some("Artist"), &some("Album"), &None).unwrap();
+        assert!(uri.starts_with("mpris:") && uri.len() == "mpris:".len() + 16, "{uri}");
+        // Stable across runs, since sticker keys depend on it
+        assert_eq!(track_uri(&some("Title"), &some("Artist"), &some("Album"), &None), Some(uri.clone()));
+        assert_eq!(track_uri(&some("Title"), &None, &None, &None), track_uri(&some("Title"), &None, &None, &None));
+        assert_ne!(track_uri(&some("Title"), &some("Artist"), &None, &None), Some(uri));
+        // Fields are separated, so moving text between them changes the URI
+        assert_ne!(track_uri(&some("ab"), &some("c"), &None, &None), track_uri(&some("a"), &some("bc"), &None, &None));
+        assert_ne!(track_uri(&some("x"), &None, &None, &None), track_uri(&None, &some("x"), &None, &None));
+    }
+
+    #[test]
+    fn partition_name_strips_mpris_prefix() {
+        assert_eq!(partition_name("org.mpris.MediaPlayer2.vlc"), "vlc");
+        assert_eq!(partition_name("org.mpris.MediaPlayer2.firefox.instance_1_42"), "firefox.instance_1_42");
+        // Names without the prefix are kept as they are
+        assert_eq!(partition_name("vlc"), "vlc");
+        assert_eq!(partition_name("org.mpris.MediaPlayer2"), "org.mpris.MediaPlayer2");
+    }
+}
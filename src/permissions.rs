@@ -0,0 +1,133 @@
+use std::ops::{BitAnd, BitOr};
+
+/// Set of MPD permission levels, as in MPD's permission configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Permissions(u8);
+
+const PERMISSION_NAMES: [(&str, Permissions); 4] = [
+    ("read", Permissions::READ),
+    ("add", Permissions::ADD),
+    ("control", Permissions::CONTROL),
+    ("admin", Permissions::ADMIN),
+];
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+    pub const READ: Permissions = Permissions(1);
+    pub const ADD: Permissions = Permissions(2);
+    pub const CONTROL: Permissions = Permissions(4);
+    pub const ADMIN: Permissions = Permissions(8);
+    pub const ALL: Permissions = Permissions(1 | 2 | 4 | 8);
+
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Parse a comma separated list like "read,add,control,admin", or "none"
+    pub fn parse(value: &str) -> anyhow::Result<Permissions> {
+        let mut permissions = Permissions::NONE;
+        for name in value.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            if name == "none" {
+                continue;
+            }
+            let Some((_, permission)) = PERMISSION_NAMES.iter().find(|(n, _)| *n == name) else {
+                return Err(anyhow::anyhow!("Unknown permission {name}, expected one of read, add, control, admin"));
+            };
+            permissions = permissions | *permission;
+        }
+        Ok(permissions)
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, other: Permissions) -> Permissions {
+        Permissions(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Permissions {
+    type Output = Permissions;
+
+    fn bitand(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & other.0)
+    }
+}
+
+impl std::fmt::Display for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<&str> = PERMISSION_NAMES.iter()
+            .filter(|(_, permission)| self.contains(*permission))
+            .map(|(name, _)| *name)
+            .collect();
+        match names.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", names.join(",")),
+        }
+    }
+}
+
+/// A password and the permissions it grants, configured like MPD's "password@permissions"
+#[derive(Debug, Clone)]
+pub struct PasswordEntry {
+    pub password: String,
+    pub permissions: Permissions,
+}
+
+impl std::str::FromStr for PasswordEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<PasswordEntry> {
+        let Some((password, permissions)) = value.rsplit_once('@') else {
+            return Err(anyhow::anyhow!("Expected PASSWORD@PERMISSIONS, e.g. secret@read,control"));
+        };
+        if password.is_empty() {
+            return Err(anyhow::anyhow!("Empty password"));
+        }
+        Ok(PasswordEntry {
+            password: password.to_string(),
+            permissions: Permissions::parse(permissions)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permissions_round_trip() {
+        for value in ["none", "read", "read,control", "read,add,control,admin"] {
+            assert_eq!(Permissions::parse(value).unwrap().to_string(), value);
+        }
+        assert_eq!(Permissions::parse("admin, read").unwrap().to_string(), "read,admin");
+        assert_eq!(Permissions::parse("").unwrap(), Permissions::NONE);
+        assert_eq!(Permissions::parse(&Permissions::ALL.to_string()).unwrap(), Permissions::ALL);
+    }
+
+    #[test]
+    fn permissions_reject_unknown() {
+        assert!(Permissions::parse("read,write").is_err());
+        assert!(Permissions::parse("all").is_err());
+    }
+
+    #[test]
+    fn password_entry_parse() {
+        let entry: PasswordEntry = "secret@read,control".parse().unwrap();
+        assert_eq!(entry.password, "secret");
+        assert_eq!(entry.permissions, Permissions::READ | Permissions::CONTROL);
+        assert_eq!(format!("{}@{}", entry.password, entry.permissions), "secret@read,control");
+        // Only the last @ separates the permissions
+        let entry: PasswordEntry = "me@home@admin".parse().unwrap();
+        assert_eq!(entry.password, "me@home");
+        assert_eq!(entry.permissions, Permissions::ADMIN);
+    }
+
+    #[test]
+    fn password_entry_rejects_invalid() {
+        assert!("secret".parse::<PasswordEntry>().is_err());
+        assert!("@read".parse::<PasswordEntry>().is_err());
+        assert!("secret@write".parse::<PasswordEntry>().is_err());
+    }
+}
@@ -0,0 +1,414 @@
+use log::debug;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use mpris::{PlaybackStatus, Player, PlayerFinder};
+use regex::Regex;
+
+/// What selecting and switching players needs to know about them
+pub trait PlayerInfo {
+    fn bus_name(&self) -> &str;
+    fn unique_name(&self) -> &str;
+    fn identity(&self) -> &str;
+    fn playback_status(&self) -> Option<PlaybackStatus>;
+}
+
+impl PlayerInfo for Player {
+    fn bus_name(&self) -> &str {
+        Player::bus_name(self)
+    }
+
+    fn unique_name(&self) -> &str {
+        Player::unique_name(self)
+    }
+
+    fn identity(&self) -> &str {
+        Player::identity(self)
+    }
+
+    /// Asks the player, unlike FoundPlayer
+    fn playback_status(&self) -> Option<PlaybackStatus> {
+        self.get_playback_status().ok()
+    }
+}
+
+/// A player on the bus, with its playback status as of when it was found
+pub struct FoundPlayer {
+    pub player: Player,
+    pub status: Option<PlaybackStatus>,
+}
+
+impl PlayerInfo for FoundPlayer {
+    fn bus_name(&self) -> &str {
+        self.player.bus_name()
+    }
+
+    fn unique_name(&self) -> &str {
+        self.player.unique_name()
+    }
+
+    fn identity(&self) -> &str {
+        self.player.identity()
+    }
+
+    fn playback_status(&self) -> Option<PlaybackStatus> {
+        self.status
+    }
+}
+
+impl<P: PlayerInfo> PlayerInfo for &P {
+    fn bus_name(&self) -> &str {
+        (*self).bus_name()
+    }
+
+    fn unique_name(&self) -> &str {
+        (*self).unique_name()
+    }
+
+    fn identity(&self) -> &str {
+        (*self).identity()
+    }
+
+    fn playback_status(&self) -> Option<PlaybackStatus> {
+        (*self).playback_status()
+    }
+}
+
+/// All players on the bus, so one check can share them instead of asking the bus again
+pub fn find_players() -> anyhow::Result<Vec<FoundPlayer>> {
+    Ok(PlayerFinder::new()?
+        .find_all()?
+        .into_iter()
+        .map(|player| FoundPlayer {
+            status: player.get_playback_status().ok(),
+            player,
+        })
+        .collect())
+}
+
+/// Pattern for players, matched against the bus name, its short form, and the Identity
+#[derive(Debug, Clone)]
+pub enum PlayerPattern {
+    /// Case insensitive, with * and ? wildcards
+    Glob(String),
+    Regex(Regex),
+}
+
+impl std::str::FromStr for PlayerPattern {
+    type Err = anyhow::Error;
+
+    /// Parse "re:REGEX", or a glob otherwise
+    fn from_str(value: &str) -> anyhow::Result<PlayerPattern> {
+        if value.is_empty() {
+            return Err(anyhow::anyhow!("Empty player pattern"));
+        }
+        match value.strip_prefix("re:") {
+            Some(regex) => Ok(PlayerPattern::Regex(Regex::new(regex)?)),
+            None => Ok(PlayerPattern::Glob(value.to_lowercase())),
+        }
+    }
+}
+
+impl std::fmt::Display for PlayerPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlayerPattern::Glob(glob) => write!(f, "{glob}"),
+            PlayerPattern::Regex(regex) => write!(f, "re:{regex}"),
+        }
+    }
+}
+
+impl PartialEq for PlayerPattern {
+    fn eq(&self, other: &PlayerPattern) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| glob_matches(rest, &text[skip..])),
+        Some((c, rest)) => match text.split_first() {
+            Some((t, text_rest)) if *c == '?' || c == t => glob_matches(rest, text_rest),
+            _ => false,
+        },
+    }
+}
+
+impl PlayerPattern {
+    fn matches_text(&self, text: &str) -> bool {
+        match self {
+            PlayerPattern::Glob(glob) => {
+                let pattern: Vec<char> = glob.chars().collect();
+                let text: Vec<char> = text.to_lowercase().chars().collect();
+                glob_matches(&pattern, &text)
+            }
+            PlayerPattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+
+    pub fn matches(&self, player: &impl PlayerInfo) -> bool {
+        self.matches_names(player.bus_name(), player.identity())
+    }
+
+    /// Match a player known only by name, like one listed in the outputs
+    pub fn matches_names(&self, bus_name: &str, identity: &str) -> bool {
+        self.matches_text(bus_name) ||
+            self.matches_text(bus_name.strip_prefix("org.mpris.MediaPlayer2.").unwrap_or(bus_name)) ||
+            self.matches_text(identity)
+    }
+}
+
+/// Which players may be bridged automatically, and which ones are preferred
+#[derive(Debug, Clone, Default)]
+pub struct SelectionPolicy {
+    /// If not empty, only matching players are selected
+    pub allow: Vec<PlayerPattern>,
+    pub deny: Vec<PlayerPattern>,
+    /// Players matching earlier patterns are preferred
+    pub priority: Vec<PlayerPattern>,
+}
+
+impl SelectionPolicy {
+    pub fn is_allowed(&self, player: &impl PlayerInfo) -> bool {
+        if self.deny.iter().any(|pattern| pattern.matches(player)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.matches(player))
+    }
+
+    /// Lower is preferred, players without matching priority pattern come last
+    pub fn priority(&self, player: &impl PlayerInfo) -> usize {
+        self.priority.iter()
+            .position(|pattern| pattern.matches(player))
+            .unwrap_or(self.priority.len())
+    }
+}
+
+/// When to switch away from the bridged player automatically
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SwitchMode {
+    /// Keep the bridged player until it disappears
+    Pinned,
+    /// Switch to a playing player when the bridged one is not playing, or to a playing one with higher priority
+    #[default]
+    Playing,
+    /// Switch to whichever player started playing most recently
+    Follow,
+}
+
+impl std::str::FromStr for SwitchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<SwitchMode> {
+        match value {
+            "pinned" => Ok(SwitchMode::Pinned),
+            "playing" => Ok(SwitchMode::Playing),
+            "follow" => Ok(SwitchMode::Follow),
+            _ => Err(anyhow::anyhow!("Unknown switch mode {value}, expected pinned, playing or follow")),
+        }
+    }
+}
+
+/// Playback history of all players, to decide when to switch the bridged player
+#[derive(Default)]
+pub struct SwitchState {
+    /// When each currently playing player started playing, by bus name
+    playing_since: HashMap<String, Instant>,
+    /// Player bridged before an automatic switch, to return to once the new one stops
+    previous: Option<String>,
+}
+
+impl SwitchState {
+    pub fn update(&mut self, players: &[impl PlayerInfo]) {
+        let now = Instant::now();
+        let mut playing_since = HashMap::new();
+        for player in players {
+            if player.playback_status() != Some(PlaybackStatus::Playing) {
+                continue;
+            }
+            let since = self.playing_since.get(player.bus_name()).copied().unwrap_or(now);
+            playing_since.insert(player.bus_name().to_string(), since);
+        }
+        self.playing_since = playing_since;
+    }
+
+    /// Index of the player to switch to, if any
+    pub fn choose<P: PlayerInfo>(
+        &self,
+        current: &impl PlayerInfo,
+        players: &[P],
+        policy: &SelectionPolicy,
+        mode: SwitchMode,
+        delay: Duration,
+    ) -> Option<usize> {
+        let current_since = self.playing_since.get(current.bus_name()).copied();
+        // Short bursts of playback, like notification sounds, should not steal the bridge
+        let settled_since = |player: &P| self.playing_since.get(player.bus_name())
+            .copied()
+            .filter(|since| since.elapsed() >= delay);
+        let others = players.iter()
+            .enumerate()
+            .filter(|(_, player)| player.unique_name() != current.unique_name());
+        let target = match mode {
+            SwitchMode::Pinned => None,
+            SwitchMode::Playing => others
+                .filter(|(_, player)| settled_since(player).is_some())
+                .filter(|(_, player)| current_since.is_none() || policy.priority(player) < policy.priority(current))
+                .min_by_key(|(_, player)| policy.priority(player))
+                .map(|(i, _)| i),
+            SwitchMode::Follow => others
+                .filter_map(|(i, player)| settled_since(player).map(|since| (i, since)))
+                .filter(|(_, since)| current_since.is_none_or(|current_since| *since > current_since))
+                .max_by_key(|(_, since)| *since)
+                .map(|(i, _)| i),
+        };
+        if target.is_some() || current_since.is_some() || mode == SwitchMode::Pinned {
+            return target;
+        }
+        let previous = self.previous.as_deref()?;
+        debug!("Bridged player stopped, trying to restore {previous}");
+        players.iter().position(|player| player.bus_name() == previous && player.unique_name() != current.unique_name())
+    }
+
+    /// Time until the next playing player has been playing for the delay
+    pub fn next_settle(&self, delay: Duration) -> Option<Duration> {
+        self.playing_since.values()
+            .map(|since| delay.saturating_sub(since.elapsed()))
+            .filter(|left| !left.is_zero())
+            .min()
+    }
+
+    /// Remember an automatic switch, so we can return to the previous player later
+    pub fn switched(&mut self, from: &impl PlayerInfo, to: &impl PlayerInfo) {
+        self.previous = match self.previous.as_deref() == Some(to.bus_name()) {
+            true => None,
+            false => Some(from.bus_name().to_string()),
+        };
+    }
+
+    /// The player to return to after the bridged one disappeared
+    pub fn take_previous(&mut self) -> Option<String> {
+        self.previous.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestPlayer {
+        bus_name: String,
+        status: PlaybackStatus,
+    }
+
+    impl PlayerInfo for TestPlayer {
+        fn bus_name(&self) -> &str {
+            &self.bus_name
+        }
+
+        fn unique_name(&self) -> &str {
+            &self.bus_name
+        }
+
+        fn identity(&self) -> &str {
+            &self.bus_name
+        }
+
+        fn playback_status(&self) -> Option<PlaybackStatus> {
+            Some(self.status)
+        }
+    }
+
+    fn player(name: &str, status: PlaybackStatus) -> TestPlayer {
+        TestPlayer {
+            bus_name: format!("org.mpris.MediaPlayer2.{name}"),
+            status,
+        }
+    }
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        glob_matches(&pattern.chars().collect::<Vec<_>>(), &text.chars().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("vlc", "vlc"));
+        assert!(!glob("vlc", "vlc2"));
+        assert!(glob("*", ""));
+        assert!(glob("fire*", "firefox.instance_1_42"));
+        assert!(glob("*fox*", "firefox.instance_1_42"));
+        assert!(glob("spot?fy", "spotify"));
+        assert!(!glob("spot?fy", "spotfy"));
+        assert!(glob("*.instance*", "chromium.instance123"));
+        assert!(!glob("chromium.*", "chromium"));
+    }
+
+    #[test]
+    fn player_pattern_from_str() {
+        assert_eq!("VLC".parse::<PlayerPattern>().unwrap(), PlayerPattern::Glob("vlc".to_string()));
+        assert!(matches!("re:^Spot".parse::<PlayerPattern>().unwrap(), PlayerPattern::Regex(regex) if regex.as_str() == "^Spot"));
+        assert_eq!("re:^Spot".parse::<PlayerPattern>().unwrap().to_string(), "re:^Spot");
+        assert!("".parse::<PlayerPattern>().is_err());
+        assert!("re:(".parse::<PlayerPattern>().is_err());
+    }
+
+    #[test]
+    fn player_pattern_matches_names() {
+        let glob: PlayerPattern = "Fire*".parse().unwrap();
+        assert!(glob.matches_names("org.mpris.MediaPlayer2.firefox.instance_1_42", "Mozilla Firefox"));
+        assert!(!glob.matches_names("org.mpris.MediaPlayer2.vlc", "VLC media player"));
+        // The Identity is matched too
+        let glob: PlayerPattern = "*media player".parse().unwrap();
+        assert!(glob.matches_names("org.mpris.MediaPlayer2.vlc", "VLC media player"));
+        // Regexes are case sensitive
+        let regex: PlayerPattern = "re:^Spot".parse().unwrap();
+        assert!(regex.matches_names("org.mpris.MediaPlayer2.spotify", "Spotify"));
+        assert!(!regex.matches_names("org.mpris.MediaPlayer2.spotify", "spotify"));
+    }
+
+    #[test]
+    fn switch_waits_for_the_settle_delay() {
+        let players = [player("vlc", PlaybackStatus::Paused), player("spotify", PlaybackStatus::Playing)];
+        let mut switch = SwitchState::default();
+        switch.update(&players);
+        let policy = SelectionPolicy::default();
+        let delay = Duration::from_secs(3600);
+        // A player that just started playing does not take over yet
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Playing, delay), None);
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Follow, delay), None);
+        assert!(switch.next_settle(delay).is_some_and(|left| left > Duration::from_secs(3500)));
+        // Without a delay it does right away
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Playing, Duration::ZERO), Some(1));
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Follow, Duration::ZERO), Some(1));
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Pinned, Duration::ZERO), None);
+        assert_eq!(switch.next_settle(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn switch_keeps_a_playing_player_unless_preferred() {
+        let players = [player("vlc", PlaybackStatus::Playing), player("spotify", PlaybackStatus::Playing)];
+        let mut switch = SwitchState::default();
+        switch.update(&players);
+        let mut policy = SelectionPolicy::default();
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Playing, Duration::ZERO), None);
+        policy.priority = vec!["spotify".parse().unwrap()];
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Playing, Duration::ZERO), Some(1));
+    }
+
+    #[test]
+    fn switch_returns_to_the_previous_player() {
+        let mut players = [player("vlc", PlaybackStatus::Paused), player("spotify", PlaybackStatus::Playing)];
+        let mut switch = SwitchState::default();
+        switch.update(&players);
+        let policy = SelectionPolicy::default();
+        assert_eq!(switch.choose(&players[0], &players, &policy, SwitchMode::Playing, Duration::ZERO), Some(1));
+        switch.switched(&players[0], &players[1]);
+        players[1].status = PlaybackStatus::Stopped;
+        switch.update(&players);
+        assert_eq!(switch.choose(&players[1], &players, &policy, SwitchMode::Playing, Duration::ZERO), Some(0));
+        switch.switched(&players[1], &players[0]);
+        assert_eq!(switch.take_previous(), None);
+    }
+}
@@ -0,0 +1,310 @@
+use log::{debug, info, trace, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::MatchRule;
+use dbus::Message;
+use mpris::{Metadata, MetadataValue, PlaybackStatus};
+use tokio::sync::mpsc;
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const MPRIS_BUS_NAME_PREFIX: &str = "org.mpris.MediaPlayer2";
+
+/// How often the watcher picks up changes of the watched players
+const WATCH_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub enum PlayerEvent {
+    /// Properties of a watched player changed or it seeked
+    Changed,
+    /// A player appeared or disappeared
+    NameOwnerChanged,
+}
+
+/// Changes of the bridged player as sent in signals, so it does not need to be read again
+#[derive(Debug, Default)]
+pub struct BridgedChanges {
+    pub playback_status: Option<PlaybackStatus>,
+    pub metadata: Option<Metadata>,
+    /// Position after a seek
+    pub position: Option<Duration>,
+    /// Something changed that the signal does not tell, like an invalidated property
+    pub reread: bool,
+}
+
+impl BridgedChanges {
+    pub fn is_empty(&self) -> bool {
+        self.playback_status.is_none() && self.metadata.is_none() && self.position.is_none() && !self.reread
+    }
+}
+
+/// Changes the observer has not looked at yet
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub bridged: BridgedChanges,
+    /// A player with connections bound to its partition changed
+    pub partitions: bool,
+    /// Another player started or stopped playing
+    pub others: bool,
+}
+
+/// Players the observer wants to hear about, by unique name
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Watched {
+    bridged: Option<String>,
+    partitions: HashSet<String>,
+    /// Whether playback status changes of all other players matter too
+    others: bool,
+}
+
+/// Changes the observer has not looked at yet, kept apart from the wakeups so those can be coalesced
+#[derive(Default)]
+pub struct PendingChanges {
+    names: AtomicBool,
+    changes: Mutex<Changes>,
+    watched: Mutex<Watched>,
+    watched_changed: AtomicBool,
+}
+
+impl PendingChanges {
+    /// Whether players appeared or disappeared since the last call
+    pub fn take_names(&self) -> bool {
+        self.names.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn take(&self) -> Changes {
+        match self.changes.lock() {
+            Ok(mut changes) => std::mem::take(&mut *changes),
+            Err(_) => Changes::default(),
+        }
+    }
+
+    /// Only signals of these players wake up the observer, other players only when they start or stop
+    /// playing, and only if others is set
+    pub fn watch(&self, bridged: Option<&str>, partitions: HashSet<String>, others: bool) {
+        let watched = Watched {
+            bridged: bridged.map(|name| name.to_string()),
+            partitions,
+            others,
+        };
+        let Ok(mut current) = self.watched.lock() else {
+            return;
+        };
+        if *current != watched {
+            *current = watched;
+            self.watched_changed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn watched(&self) -> Watched {
+        self.watched.lock().map(|watched| watched.clone()).unwrap_or_default()
+    }
+
+    /// Record the changes of a signal, returns whether the observer needs to look at them
+    fn update(&self, update: impl FnOnce(&Watched, &mut Changes) -> bool) -> bool {
+        let (Ok(watched), Ok(mut changes)) = (self.watched.lock(), self.changes.lock()) else {
+            return false;
+        };
+        update(&watched, &mut changes)
+    }
+}
+
+/// Wake up the observer, a pending wakeup already covers any further changes
+fn notify(events_tx: &mpsc::Sender<PlayerEvent>, event: PlayerEvent) {
+    if let Err(mpsc::error::TrySendError::Closed(_)) = events_tx.try_send(event) {
+        debug!("Observer is gone, ignoring player signal");
+    }
+}
+
+fn properties_changed(pending: &PendingChanges, message: &Message) -> bool {
+    let Some(sender) = message.sender() else {
+        return false;
+    };
+    let Ok((interface, changed, invalidated)) = message.read3::<&str, HashMap<String, MetadataValue>, Vec<String>>() else {
+        // Cannot tell what changed, so look at the player again if it matters
+        return pending.update(|watched, changes| match watched.bridged.as_deref() == Some(&sender) {
+            true => {
+                changes.bridged.reread = true;
+                true
+            }
+            false => false,
+        });
+    };
+    // Only the player interface carries playback state, ignore the root interface
+    if interface != MPRIS_PLAYER_INTERFACE {
+        return false;
+    }
+    let is_invalidated = |property: &str| invalidated.iter().any(|name| name == property);
+    let status_changed = changed.contains_key("PlaybackStatus") || is_invalidated("PlaybackStatus");
+    let relevant = status_changed || changed.contains_key("Metadata") || is_invalidated("Metadata");
+    pending.update(|watched, changes| {
+        if watched.bridged.as_deref() == Some(&sender) {
+            let bridged = &mut changes.bridged;
+            match changed.get("PlaybackStatus") {
+                Some(MetadataValue::String(status)) => match status.parse() {
+                    Ok(status) => bridged.playback_status = Some(status),
+                    Err(_) => bridged.reread = true,
+                },
+                Some(_) => bridged.reread = true,
+                None => {}
+            }
+            match changed.get("Metadata") {
+                Some(MetadataValue::Map(metadata)) => bridged.metadata = Some(Metadata::from(metadata.clone())),
+                Some(_) => bridged.reread = true,
+                None => {}
+            }
+            if is_invalidated("PlaybackStatus") || is_invalidated("Metadata") {
+                bridged.reread = true;
+            }
+            relevant
+        } else if watched.partitions.contains(&*sender) {
+            changes.partitions |= relevant;
+            relevant
+        } else {
+            changes.others |= watched.others && status_changed;
+            watched.others && status_changed
+        }
+    })
+}
+
+fn seeked(pending: &PendingChanges, message: &Message) -> bool {
+    let Some(sender) = message.sender() else {
+        return false;
+    };
+    let position = message.read1::<i64>().ok().map(|micros| Duration::from_micros(micros.max(0) as u64));
+    pending.update(|watched, changes| {
+        if watched.bridged.as_deref() == Some(&sender) {
+            match position {
+                Some(position) => changes.bridged.position = Some(position),
+                None => changes.bridged.reread = true,
+            }
+            true
+        } else if watched.partitions.contains(&*sender) {
+            changes.partitions = true;
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// Match rules for signals of the watched players; the bus only filters by sender if all other
+/// players are of no interest
+fn watch_rules(watched: &Watched) -> Vec<(MatchRule<'static>, bool)> {
+    let properties_rule = || MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+        .with_path(MPRIS_PATH);
+    let seeked_rule = || MatchRule::new_signal(MPRIS_PLAYER_INTERFACE, "Seeked").with_path(MPRIS_PATH);
+    let names: Vec<&String> = watched.bridged.iter().chain(&watched.partitions).collect();
+    let mut rules = Vec::new();
+    if watched.others {
+        rules.push((properties_rule(), true));
+    }
+    for name in names {
+        if !watched.others {
+            rules.push((properties_rule().with_sender(name.clone()), true));
+        }
+        rules.push((seeked_rule().with_sender(name.clone()), false));
+    }
+    rules
+}
+
+/// Replace the match rules of previously watched players with those of the currently watched ones
+fn update_watched(
+    connection: &Connection,
+    tokens: &mut Vec<Token>,
+    pending: &Arc<PendingChanges>,
+    events_tx: &mpsc::Sender<PlayerEvent>,
+) -> anyhow::Result<()> {
+    for token in tokens.drain(..) {
+        connection.remove_match(token)?;
+    }
+    let watched = pending.watched();
+    trace!("Watching signals of {watched:?}");
+    for (rule, is_properties) in watch_rules(&watched) {
+        let pending = pending.clone();
+        let tx = events_tx.clone();
+        let token = connection.add_match(rule, move |_: (), _, message| {
+            let wake = match is_properties {
+                true => properties_changed(&pending, message),
+                false => seeked(&pending, message),
+            };
+            if wake {
+                notify(&tx, PlayerEvent::Changed);
+            }
+            true
+        })?;
+        tokens.push(token);
+    }
+    // Signals may have been missed while the rules were replaced
+    let reread = pending.update(|watched, changes| {
+        changes.bridged.reread |= watched.bridged.is_some();
+        changes.partitions |= !watched.partitions.is_empty();
+        watched.bridged.is_some() || !watched.partitions.is_empty()
+    });
+    if reread {
+        notify(events_tx, PlayerEvent::Changed);
+    }
+    Ok(())
+}
+
+fn subscribe(events_tx: &mpsc::Sender<PlayerEvent>, pending: Arc<PendingChanges>) -> anyhow::Result<Connection> {
+    let connection = Connection::new_session()?;
+    // Let the bus filter by name, so we are not woken up for every client connecting to it
+    let name_owner_rule = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+        .with_sender("org.freedesktop.DBus");
+    connection.add_match_no_cb(&format!("{},arg0namespace='{MPRIS_BUS_NAME_PREFIX}'", name_owner_rule.match_str()))?;
+    let tx = events_tx.clone();
+    connection.start_receive(name_owner_rule, Box::new(move |message, _| {
+        let Ok(name) = message.read1::<&str>() else {
+            return true;
+        };
+        if name.strip_prefix(MPRIS_BUS_NAME_PREFIX).is_some_and(|rest| rest.starts_with('.')) {
+            debug!("Owner of {name} changed");
+            // Unlike property changes, these must not get lost in a pending wakeup, so they are also flagged
+            pending.names.store(true, Ordering::SeqCst);
+            notify(&tx, PlayerEvent::NameOwnerChanged);
+        }
+        true
+    }));
+    Ok(connection)
+}
+
+/// Watch PropertiesChanged and Seeked of the players the observer asks for, and players coming and going,
+/// on a separate thread; the sender is dropped when the watcher fails, so callers can fall back to polling
+pub fn spawn_watcher(events_tx: mpsc::Sender<PlayerEvent>, pending: Arc<PendingChanges>) -> anyhow::Result<()> {
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("mpris-signals".to_string())
+        .spawn(move || {
+            let connection = match subscribe(&events_tx, pending.clone()) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+            let mut tokens = Vec::new();
+            while !events_tx.is_closed() {
+                if pending.watched_changed.swap(false, Ordering::SeqCst) {
+                    if let Err(e) = update_watched(&connection, &mut tokens, &pending, &events_tx) {
+                        warn!("Stopped watching MPRIS signals: {e}");
+                        return;
+                    }
+                }
+                if let Err(e) = connection.process(WATCH_UPDATE_INTERVAL) {
+                    warn!("Stopped watching MPRIS signals: {e}");
+                    return;
+                }
+            }
+        })?;
+    ready_rx.recv()??;
+    info!("Watching MPRIS signals");
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use log::{debug, info};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+pub fn default_sticker_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("mpd-mpris-bridge").join("stickers.json"))
+}
+
+/// Sticker values per track URI and sticker name
+pub struct StickerDb {
+    path: Option<PathBuf>,
+    stickers: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl StickerDb {
+    /// Sticker database that is only kept in memory
+    pub fn in_memory() -> StickerDb {
+        StickerDb {
+            path: None,
+            stickers: BTreeMap::new(),
+        }
+    }
+
+    /// Load stickers from a JSON file, which does not need to exist yet
+    pub fn load(path: &Path) -> anyhow::Result<StickerDb> {
+        let stickers = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid sticker database {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No sticker database at {} yet", path.display());
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        info!("Loaded stickers for {} tracks from {}", stickers.len(), path.display());
+        Ok(StickerDb {
+            path: Some(path.to_path_buf()),
+            stickers,
+        })
+    }
+
+    fn persist(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so we never leave a truncated database behind
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&self.stickers)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Write the database again, e.g. on shutdown in case an earlier write failed
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.persist()
+    }
+
+    pub fn get(&self, uri: &str, name: &str) -> Option<&str> {
+        self.stickers.get(uri)?.get(name).map(|v| v.as_str())
+    }
+
+    pub fn list(&self, uri: &str) -> Option<&BTreeMap<String, String>> {
+        self.stickers.get(uri)
+    }
+
+    pub fn set(&mut self, uri: &str, name: &str, value: &str) -> anyhow::Result<()> {
+        self.stickers.entry(uri.to_string()).or_default().insert(name.to_string(), value.to_string());
+        self.persist()
+    }
+
+    /// Add delta to a numeric sticker, treating a missing sticker as 0, and return the new value
+    pub fn add(&mut self, uri: &str, name: &str, delta: i64) -> anyhow::Result<i64> {
+        let current = match self.get(uri, name) {
+            Some(value) => value.parse::<i64>()
+                .map_err(|_| anyhow::anyhow!("Sticker {name} is not an integer: {value}"))?,
+            None => 0,
+        };
+        let value = current.saturating_add(delta);
+        self.set(uri, name, &value.to_string())?;
+        Ok(value)
+    }
+
+    /// Delete one or all stickers of a track, returns false if there was nothing to delete
+    pub fn delete(&mut self, uri: &str, name: Option<&str>) -> anyhow::Result<bool> {
+        let Some(track_stickers) = self.stickers.get_mut(uri) else {
+            return Ok(false);
+        };
+        let deleted = match name {
+            Some(name) => track_stickers.remove(name).is_some(),
+            None => {
+                track_stickers.clear();
+                true
+            }
+        };
+        if track_stickers.is_empty() {
+            self.stickers.remove(uri);
+        }
+        if deleted {
+            self.persist()?;
+        }
+        Ok(deleted)
+    }
+
+    /// All (uri, value) pairs for a sticker name below a URI prefix that pass the filter
+    pub fn find<F: Fn(&str) -> bool>(&self, uri_prefix: &str, name: &str, filter: F) -> Vec<(&str, &str)> {
+        self.stickers.iter()
+            .filter(|(uri, _)| uri.starts_with(uri_prefix))
+            .filter_map(|(uri, track_stickers)| {
+                track_stickers.get(name).map(|value| (uri.as_str(), value.as_str()))
+            })
+            .filter(|(_, value)| filter(value))
+            .collect()
+    }
+
+    pub fn names(&self) -> BTreeSet<&str> {
+        self.stickers.values()
+            .flat_map(|track_stickers| track_stickers.keys().map(|name| name.as_str()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_get_list() {
+        let mut db = StickerDb::in_memory();
+        assert_eq!(db.get("file:///a.mp3", "rating"), None);
+        db.set("file:///a.mp3", "rating", "5").unwrap();
+        db.set("file:///a.mp3", "note", "great").unwrap();
+        db.set("file:///a.mp3", "rating", "4").unwrap();
+        assert_eq!(db.get("file:///a.mp3", "rating"), Some("4"));
+        let list: Vec<_> = db.list("file:///a.mp3").unwrap().iter().collect();
+        assert_eq!(list, [(&"note".to_string(), &"great".to_string()), (&"rating".to_string(), &"4".to_string())]);
+        assert!(db.list("file:///b.mp3").is_none());
+    }
+
+    #[test]
+    fn inc_and_dec() {
+        let mut db = StickerDb::in_memory();
+        assert_eq!(db.add("a", "playcount", 1).unwrap(), 1);
+        assert_eq!(db.add("a", "playcount", 2).unwrap(), 3);
+        assert_eq!(db.add("a", "playcount", -5).unwrap(), -2);
+        assert_eq!(db.get("a", "playcount"), Some("-2"));
+        db.set("a", "note", "text").unwrap();
+        assert!(db.add("a", "note", 1).is_err());
+        assert_eq!(db.get("a", "note"), Some("text"));
+    }
+
+    #[test]
+    fn delete() {
+        let mut db = StickerDb::in_memory();
+        db.set("a", "rating", "5").unwrap();
+        db.set("a", "note", "great").unwrap();
+        db.set("b", "rating", "1").unwrap();
+        assert!(db.delete("a", Some("rating")).unwrap());
+        assert!(!db.delete("a", Some("rating")).unwrap());
+        assert_eq!(db.get("a", "note"), Some("great"));
+        assert!(db.delete("b", None).unwrap());
+        assert!(db.list("b").is_none());
+        assert!(!db.delete("b", None).unwrap());
+        // Deleting the last sticker forgets the track
+        assert!(db.delete("a", Some("note")).unwrap());
+        assert!(db.list("a").is_none());
+    }
+
+    #[test]
+    fn find_and_names() {
+        let mut db = StickerDb::in_memory();
+        db.set("file:///music/a.mp3", "rating", "5").unwrap();
+        db.set("file:///music/b.mp3", "rating", "2").unwrap();
+        db.set("file:///other/c.mp3", "rating", "4").unwrap();
+        db.set("mpris:0123456789abcdef", "note", "radio").unwrap();
+        assert_eq!(db.find("file:///music/", "rating", |_| true), [("file:///music/a.mp3", "5"), ("file:///music/b.mp3", "2")]);
+        assert_eq!(db.find("", "rating", |value| value > "3"), [("file:///music/a.mp3", "5"), ("file:///other/c.mp3", "4")]);
+        assert!(db.find("", "playcount", |_| true).is_empty());
+        assert_eq!(db.names(), BTreeSet::from(["note", "rating"]));
+    }
+
+    #[test]
+    fn persists_to_file() {
+        let dir = std::env::temp_dir().join(format!("mpd-mpris-bridge-stickers-{}", std::process::id()));
+        let path = dir.join("stickers.json");
+        let mut db = StickerDb::load(&path).unwrap();
+        db.set("a", "rating", "5").unwrap();
+        db.set("b", "note", "gone").unwrap();
+        db.delete("b", None).unwrap();
+        let db = StickerDb::load(&path).unwrap();
+        assert_eq!(db.get("a", "rating"), Some("5"));
+        assert!(db.list("b").is_none());
+        std::fs::write(&path, "not json").unwrap();
+        assert!(StickerDb::load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use log::{debug, info, warn};
+
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum ActivatedListener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+fn socket_family(fd: RawFd) -> std::io::Result<libc::c_int> {
+    // SAFETY: zeroed sockaddr_storage is a valid value, and getsockname only writes up to len
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int)
+}
+
+/// Take over listening sockets passed via LISTEN_FDS, if they are meant for this process
+pub fn listen_fds() -> anyhow::Result<Vec<ActivatedListener>> {
+    let Ok(pid) = std::env::var("LISTEN_PID") else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        debug!("Ignoring LISTEN_FDS for other process {pid}");
+        return Ok(Vec::new());
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .map_err(|_| anyhow::anyhow!("LISTEN_PID set without LISTEN_FDS"))?
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid LISTEN_FDS"))?;
+    // The variables are left set, unsetting them is not thread safe and any child would see another pid
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // SAFETY: systemd passes ownership of these descriptors to us, and nothing else uses them
+        let listener = match socket_family(fd)? {
+            libc::AF_INET | libc::AF_INET6 => ActivatedListener::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+            libc::AF_UNIX => ActivatedListener::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) }),
+            family => return Err(anyhow::anyhow!("Unsupported socket family {family} for passed fd {fd}")),
+        };
+        listeners.push(listener);
+    }
+    info!("Received {count} listening sockets from systemd");
+    Ok(listeners)
+}
+
+/// Service manager notifications, see sd_notify(3); does nothing when not run by systemd
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog_interval: Option<Duration>,
+    last_watchdog: Mutex<Option<Instant>>,
+    last_status: Mutex<String>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Notifier {
+        let socket = std::env::var("NOTIFY_SOCKET").ok().and_then(|path| {
+            let addr = match path.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(&path),
+            };
+            match (UnixDatagram::unbound(), addr) {
+                (Ok(socket), Ok(addr)) => Some((socket, addr)),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("Cannot use notify socket {path}: {e}");
+                    None
+                }
+            }
+        });
+        let watchdog_pid_matches = std::env::var("WATCHDOG_PID")
+            .map(|pid| pid.parse::<u32>().ok() == Some(std::process::id()))
+            .unwrap_or(true);
+        let watchdog_interval = std::env::var("WATCHDOG_USEC").ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && watchdog_pid_matches)
+            .map(Duration::from_micros);
+        if let Some(interval) = watchdog_interval {
+            info!("Enabled systemd watchdog with interval {interval:?}");
+        }
+        Notifier {
+            socket,
+            watchdog_interval,
+            last_watchdog: Mutex::new(None),
+            last_status: Mutex::new(String::new()),
+        }
+    }
+
+    fn notify(&self, message: &str) {
+        let Some((socket, addr)) = &self.socket else {
+            return;
+        };
+        if let Err(e) = socket.send_to_addr(message.as_bytes(), addr) {
+            warn!("Failed to notify systemd: {e}");
+        }
+    }
+
+    pub fn ready(&self) {
+        debug!("Notifying systemd about readiness");
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Update the status line shown by systemctl, if it changed
+    pub fn status(&self, status: &str) {
+        let Ok(mut last_status) = self.last_status.lock() else {
+            return;
+        };
+        if *last_status == status {
+            return;
+        }
+        *last_status = status.to_string();
+        self.notify(&format!("STATUS={status}"));
+    }
+
+    /// Shorten a wait so watchdog is called often enough, even if the wait is not interrupted
+    pub fn watchdog_delay(&self, delay: Duration) -> Duration {
+        match self.watchdog_interval {
+            // Pings are sent at most every half interval, so wake up twice as often
+            Some(interval) => delay.min(interval / 4),
+            None => delay,
+        }
+    }
+
+    /// Tell systemd we are still alive, at most every half watchdog interval
+    pub fn watchdog(&self) {
+        let Some(interval) = self.watchdog_interval else {
+            return;
+        };
+        let Ok(mut last_watchdog) = self.last_watchdog.lock() else {
+            return;
+        };
+        if last_watchdog.is_some_and(|last| last.elapsed() < interval / 2) {
+            return;
+        }
+        *last_watchdog = Some(Instant::now());
+        self.notify("WATCHDOG=1");
+    }
+}